@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 use std::rc::Rc;
+use serde::Deserialize;
 
 
 // Abstract over various memory management strategies.
@@ -10,7 +11,7 @@ pub type Map<T> = HashMap<String, Node<T>>;
 
 
 // Arithmetic and logic operations
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BinOp {
     Add,
     Sub,
@@ -32,7 +33,7 @@ pub enum BinOp {
 }
 
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum UnOp {
     Not,
     Neg,
@@ -0,0 +1,97 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Converts JSON samples on stdin, one per line, to the binary input
+// format on stdout.
+//
+// The channels given as arguments are sent in that order. If none are
+// given, channels are added as they first appear, and a new header is
+// sent each time. With `--time <key>`, frames are stamped with the
+// value of that key.
+
+use std::{
+    collections::HashMap,
+    env::args,
+    io::{stdin, stdout, BufRead, Write},
+    process::exit
+};
+
+use serde_json::Value;
+
+use udashboard::binary::Encoder;
+use udashboard::config::Float;
+
+fn too_many() -> Encoder {
+    eprintln!("Too many channels, or names too long, for a header.");
+    exit(1);
+}
+
+fn main() {
+    let mut names = Vec::new();
+    let mut time_key = None;
+    let mut args = args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--time" if time_key.is_none() => time_key = args.next(),
+            _ if arg.starts_with("--") => {
+                eprintln!("Usage: json2bin [--time <key>] [channel ...]");
+                exit(1);
+            },
+            _ => names.push(arg)
+        }
+    }
+
+    let fixed = !names.is_empty();
+    let mut encoder = Encoder::new(names).unwrap_or_else(|| too_many());
+    let stdout = stdout();
+    let mut out = stdout.lock();
+    out.write_all(&encoder.header()).expect("couldn't write output");
+
+    let stdin = stdin();
+    for line in stdin.lock().lines() {
+        let line = line.expect("couldn't read input");
+        let map = match serde_json::from_str(&line) {
+            Ok(Value::Object(map)) => map,
+            _ => {
+                eprintln!("Skipping: {}", line);
+                continue;
+            }
+        };
+
+        let mut values: HashMap<String, Float> = map.iter()
+            .filter_map(|(k, v)| v.as_f64().map(|v| (k.clone(), v as Float)))
+            .collect();
+        let time = time_key.as_ref().and_then(|key| values.remove(key));
+
+        if !fixed {
+            let mut keys: Vec<&String> = values.keys()
+                .filter(|key| !encoder.names().contains(*key))
+                .collect();
+            if !keys.is_empty() {
+                keys.sort();
+                let mut names = encoder.names().to_vec();
+                names.extend(keys.into_iter().cloned());
+                encoder = Encoder::new(names).unwrap_or_else(|| too_many());
+                out.write_all(&encoder.header()).expect("couldn't write output");
+            }
+        }
+
+        out.write_all(&encoder.frame(&values, time)).expect("couldn't write output");
+    }
+}
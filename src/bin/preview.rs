@@ -18,30 +18,51 @@
 
 use std::{
     collections::HashMap,
-    env::args
+    env::args,
+    process::exit
 };
 
 use udashboard::v1;
 use udashboard::{
+    cli,
     config::{Style, Pattern, Color},
-    data::{State, ReadSource},
+    data::State,
+    logic::Evaluator,
     windowed,
     render::{CairoRenderer, PNGRenderer},
 };
 
 fn main() {
-    let config = v1::load(args().nth(1).unwrap())
+    let options = cli::parse(args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\nUsage: preview <config> [options]", e);
+        eprintln!("{}", cli::USAGE);
+        exit(1);
+    });
+
+    let mut config = v1::load(options.config.clone())
         .expect("couldn't load config");
+    if let Some(format) = options.format {
+        config.input.format = format;
+    }
+
+    let logic = Evaluator::new(config.logic.clone());
 
     let renderer = CairoRenderer::new(
         config.screen,
-        config.pages,
+        config.pages.clone(),
+        logic.alarms(),
         Style {
             background: Pattern::Solid(Color(0.0, 0.0, 0.0, 1.0)),
             foreground: Pattern::Solid(Color(1.0, 1.0, 1.0, 1.0)),
             indicator: Pattern::Solid(Color(1.0, 0.0, 0.0, 1.0)),
-        }
+        },
+        config.slow_blink,
+        config.fast_blink
     );
 
-    windowed::run(config.screen, renderer);
+    let data = options.source
+        .open(&config)
+        .expect("couldn't open data source");
+
+    windowed::run(config.screen, renderer, data, logic, config.frame_rate);
 }
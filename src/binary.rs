@@ -0,0 +1,466 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Binary input format
+//
+// A compact alternative to JSON, for high sample rates. A stream
+// starts with a header, which names the channels once, and is followed
+// by frames, each holding a value for every channel named, in order.
+// All numbers are little-endian.
+//
+// Header:
+//   `UDB1`
+//   u16      number of channels
+//   per channel: u8 length of its name, then the name, in UTF-8
+//   u32      CRC-32 of everything before it
+//
+// Frame:
+//   0xa5 0x5a
+//   u32      sequence number, one more than the frame before
+//   f64      time in seconds, or NaN to use the time received
+//   f32      the value of each channel, or NaN if it has none
+//   u32      CRC-32 of the sequence number, time and values
+//
+// A header may be at most `MAX_HEADER` bytes long, so that a corrupt
+// one is rejected quickly. A new header may be sent at any time, to
+// change the channels. Frames whose CRC doesn't match are discarded,
+// and the reader skips ahead to the next frame or header. Gaps in the
+// sequence numbers are counted as dropped frames.
+
+use std::{
+    collections::HashMap,
+    io::Read
+};
+
+use crate::config::{Float, Input};
+use crate::data::{Sample, Shared};
+
+pub const MAGIC: [u8; 4] = *b"UDB1";
+pub const MARKER: [u8; 2] = [0xa5, 0x5a];
+pub const MAX_HEADER: usize = 4096;
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {(crc >> 1) ^ 0xedb8_8320} else {crc >> 1};
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC_TABLE: [u32; 256] = crc_table();
+
+// The CRC-32 used by zlib and Ethernet.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        CRC_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+// Writes samples in the binary format.
+pub struct Encoder {
+    names: Vec<String>,
+    sequence: u32
+}
+
+impl Encoder {
+    // Returns `None` if the names don't fit in a header.
+    pub fn new(names: Vec<String>) -> Option<Encoder> {
+        let length = 10 + names.iter().map(|name| 1 + name.len()).sum::<usize>();
+        if length > MAX_HEADER || names.iter().any(|name| name.len() > 255) {
+            return None;
+        }
+        Some(Encoder {names, sequence: 0})
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend(&(self.names.len() as u16).to_le_bytes());
+        for name in &self.names {
+            header.push(name.len() as u8);
+            header.extend(name.as_bytes());
+        }
+        let crc = crc32(&header);
+        header.extend(&crc.to_le_bytes());
+        header
+    }
+
+    // Encode the values of the channels in the header, at the given
+    // time.
+    pub fn frame(&mut self, values: &HashMap<String, Float>, time: Option<Float>) -> Vec<u8> {
+        let mut body = self.sequence.to_le_bytes().to_vec();
+        body.extend(&time.unwrap_or(Float::NAN).to_le_bytes());
+        for name in &self.names {
+            let value = values.get(name).map(|v| *v as f32).unwrap_or(f32::NAN);
+            body.extend(&value.to_le_bytes());
+        }
+
+        let mut frame = MARKER.to_vec();
+        frame.extend(&body);
+        frame.extend(&crc32(&body).to_le_bytes());
+        self.sequence = self.sequence.wrapping_add(1);
+        frame
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    // A new header named the channels.
+    Header,
+    // A frame, whose values are given by `Decoder::values`, with its
+    // time if it has one, and the number of frames dropped before it.
+    Frame {time: Option<Float>, dropped: u32},
+    // Bytes which aren't a valid frame or header were skipped.
+    Invalid
+}
+
+enum Header {
+    Incomplete,
+    Invalid,
+    // The channel names, and the length of the header.
+    Valid(Vec<String>, usize)
+}
+
+fn parse_header(buffer: &[u8]) -> Header {
+    // Anything past the longest header can't be part of it.
+    let (buffer, complete) = match buffer.get(..MAX_HEADER) {
+        Some(buffer) => (buffer, true),
+        None => (buffer, false)
+    };
+    let incomplete = if complete {Header::Invalid} else {Header::Incomplete};
+
+    let mut names = Vec::new();
+    let count = match buffer.get(4..6) {
+        Some(count) => u16::from_le_bytes([count[0], count[1]]),
+        None => return incomplete
+    };
+
+    let mut position = 6;
+    for _ in 0..count {
+        let length = match buffer.get(position) {
+            Some(length) => *length as usize,
+            None => return incomplete
+        };
+        let name = match buffer.get(position + 1..position + 1 + length) {
+            Some(name) => name,
+            None => return incomplete
+        };
+        match String::from_utf8(name.to_vec()) {
+            Ok(name) => names.push(name),
+            Err(_) => return Header::Invalid
+        }
+        position += 1 + length;
+    }
+
+    match buffer.get(position..position + 4) {
+        Some(crc) if crc32(&buffer[..position]).to_le_bytes() == crc =>
+            Header::Valid(names, position + 4),
+        Some(_) => Header::Invalid,
+        None => incomplete
+    }
+}
+
+fn f32_at(buffer: &[u8], i: usize) -> f32 {
+    f32::from_le_bytes([buffer[i], buffer[i + 1], buffer[i + 2], buffer[i + 3]])
+}
+
+// Splits a byte stream into frames.
+#[derive(Default)]
+pub struct Decoder {
+    // Channels named by the latest header.
+    names: Option<Vec<String>>,
+    // Values of the latest frame, in the order of `names`, with NaN for
+    // those it didn't have.
+    values: Vec<Float>,
+    buffer: Vec<u8>,
+    // Position in the buffer of the first byte not yet decoded.
+    start: usize,
+    // Sequence number of the last frame.
+    sequence: Option<u32>,
+    // Skipping invalid bytes, which have already been reported.
+    skipping: bool
+}
+
+impl Decoder {
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.drain(..self.start);
+        self.start = 0;
+        self.buffer.extend(bytes);
+    }
+
+    // Channels named by the latest header.
+    pub fn names(&self) -> &[String] {
+        self.names.as_deref().unwrap_or(&[])
+    }
+
+    // Values of the latest frame, in the order of `names`, with NaN
+    // for those it didn't have.
+    pub fn values(&self) -> &[Float] {
+        &self.values
+    }
+
+    // Skip the first byte, which can't start a header or frame, and
+    // report it unless we're already skipping.
+    fn skip(&mut self) -> Option<Event> {
+        self.start += 1;
+        if self.skipping {
+            None
+        } else {
+            self.skipping = true;
+            Some(Event::Invalid)
+        }
+    }
+
+    // Return the next event, or `None` if more bytes are needed.
+    pub fn decode(&mut self) -> Option<Event> {
+        loop {
+            let buffer = &self.buffer[self.start..];
+
+            if buffer.starts_with(&MAGIC) {
+                match parse_header(buffer) {
+                    Header::Incomplete => return None,
+                    Header::Invalid => match self.skip() {
+                        Some(event) => return Some(event),
+                        None => continue
+                    },
+                    Header::Valid(names, length) => {
+                        self.start += length;
+                        self.values = vec! {Float::NAN; names.len()};
+                        self.names = Some(names);
+                        self.sequence = None;
+                        self.skipping = false;
+                        return Some(Event::Header);
+                    }
+                }
+            }
+
+            let count = match &self.names {
+                Some(names) if buffer.starts_with(&MARKER) => names.len(),
+                _ => if buffer.is_empty() ||
+                    MAGIC.starts_with(buffer) ||
+                    MARKER.starts_with(buffer) {
+                    return None;
+                } else {
+                    match self.skip() {
+                        Some(event) => return Some(event),
+                        None => continue
+                    }
+                }
+            };
+
+            let length = MARKER.len() + 4 + 8 + 4 * count + 4;
+            if buffer.len() < length {
+                return None;
+            }
+
+            let body = &buffer[MARKER.len()..length - 4];
+            let crc = &buffer[length - 4..length];
+            if crc32(body).to_le_bytes() != crc {
+                match self.skip() {
+                    Some(event) => return Some(event),
+                    None => continue
+                }
+            }
+
+            let sequence = u32::from_le_bytes([body[0], body[1], body[2], body[3]]);
+            let mut time = [0; 8];
+            time.copy_from_slice(&body[4..12]);
+            let time = Float::from_le_bytes(time);
+
+            for (i, value) in self.values.iter_mut().enumerate() {
+                *value = f32_at(body, 12 + 4 * i) as Float;
+            }
+
+            // A sequence number which goes backwards means the sender
+            // has started over, rather than dropped frames.
+            let dropped = match self.sequence {
+                Some(last) => match sequence.wrapping_sub(last).wrapping_sub(1) {
+                    gap if gap < 1 << 31 => gap,
+                    _ => 0
+                },
+                None => 0
+            };
+
+            self.start += length;
+            self.sequence = Some(sequence);
+            self.skipping = false;
+
+            let time = if time.is_nan() {None} else {Some(time)};
+            return Some(Event::Frame {time, dropped});
+        }
+    }
+}
+
+// Merge each frame of a stream into the shared state, until the end of
+// the stream, or an I/O error.
+pub(crate) fn read_frames<R: Read>(mut src: R, sink: &Shared, input: &Input) {
+    let mut decoder = Decoder::default();
+    let mut chunk = [0; 4096];
+    // Reused from frame to frame, so that the channel names are only
+    // copied when a channel first appears.
+    let mut sample = Sample {values: HashMap::new(), time: 0.0, received: 0.0};
+
+    loop {
+        match src.read(&mut chunk) {
+            Ok(0) => {
+                if input.log_errors {
+                    eprintln!("End of input.");
+                }
+                break;
+            },
+            Ok(length) => decoder.push(&chunk[..length]),
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading input: {}", e);
+                }
+                break;
+            }
+        }
+
+        while let Some(event) = decoder.decode() {
+            match event {
+                Event::Header => sample.values.clear(),
+                Event::Frame {time, dropped} => {
+                    if dropped > 0 {
+                        if input.log_errors {
+                            eprintln!("Dropped {} frames.", dropped);
+                        }
+                        sink.dropped(dropped as usize);
+                    }
+
+                    for (name, value) in decoder.names().iter().zip(decoder.values()) {
+                        if value.is_nan() {
+                            sample.values.remove(name);
+                        } else if let Some(v) = sample.values.get_mut(name) {
+                            *v = *value;
+                        } else {
+                            sample.values.insert(name.clone(), *value);
+                        }
+                    }
+
+                    sample.received = sink.now();
+                    sample.time = time.unwrap_or(sample.received);
+                    sink.merge_ref(&sample);
+                },
+                Event::Invalid => {
+                    if input.log_errors {
+                        eprintln!("Skipping invalid input.");
+                    }
+                    sink.malformed();
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::State;
+
+    fn values(values: &[(&str, Float)]) -> HashMap<String, Float> {
+        values.iter().map(|(k, v)| (String::from(*k), *v)).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut encoder = Encoder::new(vec! {String::from("RPM"), String::from("ECT")}).unwrap();
+        let mut stream = encoder.header();
+        stream.extend(encoder.frame(&values(&[("RPM", 3000.0), ("ECT", 90.5)]), Some(10.0)));
+        // Corrupted, and then lost.
+        let mut bad = encoder.frame(&values(&[("RPM", 9999.0)]), None);
+        bad[8] ^= 1;
+        stream.extend(bad);
+        encoder.frame(&values(&[("RPM", 9999.0)]), None);
+        stream.extend(b"junk");
+        stream.extend(encoder.frame(&values(&[("RPM", 3500.0)]), None));
+
+        let mut decoder = Decoder::default();
+        let mut events = Vec::new();
+        // Split the stream, as reads might.
+        for chunk in stream.chunks(7) {
+            decoder.push(chunk);
+            while let Some(event) = decoder.decode() {
+                let values: Vec<Option<Float>> = decoder.values()
+                    .iter()
+                    .map(|v| if v.is_nan() {None} else {Some(*v)})
+                    .collect();
+                events.push((event, values));
+            }
+        }
+
+        assert_eq!(decoder.names(), &[String::from("RPM"), String::from("ECT")][..]);
+        assert_eq!(events, vec! {
+            (Event::Header, vec! {None, None}),
+            (Event::Frame {time: Some(10.0), dropped: 0}, vec! {Some(3000.0), Some(90.5)}),
+            (Event::Invalid, vec! {Some(3000.0), Some(90.5)}),
+            (Event::Frame {time: None, dropped: 2}, vec! {Some(3500.0), None})
+        });
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let mut encoder = Encoder::new(vec! {String::from("RPM")}).unwrap();
+        let mut stream = encoder.header();
+        stream.extend(encoder.frame(&values(&[("RPM", 3000.0)]), Some(1.0)));
+        let mut encoder = Encoder::new(vec! {String::from("SPEED")}).unwrap();
+        stream.extend(encoder.header());
+        stream.extend(encoder.frame(&values(&[("SPEED", 55.0)]), Some(2.0)));
+
+        let sink = Shared::new(State::new());
+        read_frames(stream.as_slice(), &sink, &crate::config::Config::empty().input);
+
+        let state = sink.snapshot(&[]);
+        assert_eq!(state.get(&String::from("RPM")), Some(3000.0));
+        assert_eq!(state.get(&String::from("SPEED")), Some(55.0));
+        assert_eq!(state.time, 2.0);
+        assert_eq!(sink.counters().malformed(), 0);
+    }
+
+    #[test]
+    fn test_bad_header() {
+        // A spurious header, claiming more channels than fit, is
+        // skipped without waiting for the rest of it.
+        let mut stream = MAGIC.to_vec();
+        stream.extend(&[0xff, 0xff]);
+        stream.extend(vec! {0x10; MAX_HEADER});
+        let encoder = Encoder::new(vec! {String::from("RPM")}).unwrap();
+        stream.extend(encoder.header());
+
+        let mut decoder = Decoder::default();
+        decoder.push(&stream);
+        assert_eq!(decoder.decode(), Some(Event::Invalid));
+        assert_eq!(decoder.decode(), Some(Event::Header));
+
+        assert!(Encoder::new(vec! {"X".repeat(256)}).is_none());
+    }
+}
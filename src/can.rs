@@ -0,0 +1,481 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// CAN bus data source
+//
+// Reads frames from a Linux SocketCAN interface, or from a log written
+// by `candump -l`, and decodes the signals declared in the config into
+// input values. Every frame which carries any signals becomes a
+// sample, holding the values of just those signals.
+//
+// Both classic frames, of up to 8 bytes, and CAN FD frames, of up to
+// 64, are read.
+//
+// Frames are stamped with the time they were read, or, from a log,
+// with their recorded time. A log is played back at the rate it was
+// recorded, except that gaps of more than `MAX_GAP` are cut short, and
+// the source ends at the end of the log, or if the interface goes away.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    mem::size_of,
+    os::unix::io::FromRawFd,
+    str,
+    thread::sleep,
+    time::Duration
+};
+
+use crate::config::{ByteOrder, CanSignal, Config, Float, Input};
+use crate::data::{Sample, Shared, ThreadedSource};
+
+// Not every version of libc defines these.
+const AF_CAN: libc::c_int = 29;
+const CAN_RAW: libc::c_int = 1;
+const SOL_CAN_RAW: libc::c_int = 101;
+const CAN_RAW_FD_FRAMES: libc::c_int = 5;
+
+// Flags in the identifier of a frame read from a socket.
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_EFF_MASK: u32 = 0x1fff_ffff;
+const CAN_SFF_MASK: u32 = 0x0000_07ff;
+
+// Longest wait, in seconds, between frames played back from a log.
+const MAX_GAP: Float = 1.0;
+
+// Size of `struct can_frame`.
+const FRAME_SIZE: usize = 16;
+// Size of `struct canfd_frame`.
+const FD_FRAME_SIZE: usize = 72;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: u32,
+    pub data: Vec<u8>
+}
+
+// The bit at the given position in a frame, numbered from the least
+// significant bit of the first byte.
+fn bit(data: &[u8], position: u32) -> Option<u64> {
+    let byte = data.get((position / 8) as usize)?;
+    Some(((byte >> (position % 8)) & 1) as u64)
+}
+
+// Decode a signal from a frame, or return `None` if the frame is too
+// short to hold it.
+pub fn decode(signal: &CanSignal, data: &[u8]) -> Option<Float> {
+    let mut raw: u64 = 0;
+
+    match signal.byte_order {
+        ByteOrder::LittleEndian => for i in 0..signal.length {
+            raw |= bit(data, signal.start + i)? << i;
+        },
+        // Bits run from the most significant down through each byte,
+        // then continue from the top of the next byte.
+        ByteOrder::BigEndian => {
+            let mut position = signal.start;
+            for _ in 0..signal.length {
+                raw = (raw << 1) | bit(data, position)?;
+                position = match position % 8 {
+                    0 => position + 15,
+                    _ => position - 1
+                };
+            }
+        }
+    }
+
+    let length = signal.length;
+    let value = if signal.signed && length < 64 && raw >> (length - 1) == 1 {
+        (raw as i64 - (1i64 << length)) as Float
+    } else if signal.signed {
+        raw as i64 as Float
+    } else {
+        raw as Float
+    };
+
+    Some(value * signal.factor + signal.offset)
+}
+
+// Decodes the configured signals from frames.
+pub struct Decoder {
+    // Signals carried by each frame identifier.
+    signals: HashMap<u32, Vec<CanSignal>>
+}
+
+impl Decoder {
+    pub fn new(signals: &[CanSignal]) -> Decoder {
+        let mut by_id: HashMap<u32, Vec<CanSignal>> = HashMap::new();
+        for signal in signals {
+            by_id.entry(signal.id).or_default().push(signal.clone());
+        }
+        Decoder {signals: by_id}
+    }
+
+    // The values of the signals carried by the frame.
+    pub fn decode(&self, frame: &Frame) -> HashMap<String, Float> {
+        let mut values = HashMap::new();
+
+        for signal in self.signals.get(&frame.id).into_iter().flatten() {
+            if let Some(value) = decode(signal, &frame.data) {
+                values.insert(signal.name.clone(), value);
+            }
+        }
+
+        values
+    }
+}
+
+// A line of a log which isn't a frame.
+#[derive(Debug, PartialEq)]
+pub struct InvalidLine;
+
+// Parse a line of a `candump -l` log, such as
+// `(1571234567.123456) can0 123#DEADBEEF`, into the frame's time and
+// the frame. Remote frames, and blank lines, are skipped.
+pub fn parse_candump(line: &str) -> Result<Option<(Float, Frame)>, InvalidLine> {
+    let mut words = line.split_whitespace();
+    let time = match words.next() {
+        Some(time) => time,
+        None => return Ok(None)
+    };
+    let _interface = words.next().ok_or(InvalidLine)?;
+    let frame = words.next().ok_or(InvalidLine)?;
+
+    let time = time
+        .strip_prefix('(')
+        .and_then(|time| time.strip_suffix(')'))
+        .and_then(|time| time.parse().ok())
+        .filter(|time: &Float| time.is_finite())
+        .ok_or(InvalidLine)?;
+    let (id, data) = frame.split_at(frame.find('#').ok_or(InvalidLine)?);
+    let id = u32::from_str_radix(id, 16).map_err(|_| InvalidLine)?;
+
+    // CAN FD frames have a second `#`, followed by a flags digit.
+    let data = match data.strip_prefix("##") {
+        Some(data) => data.get(1..).ok_or(InvalidLine)?,
+        None => &data[1..]
+    };
+
+    if data.starts_with('R') {
+        return Ok(None);
+    }
+    if data.len() % 2 == 1 {
+        return Err(InvalidLine);
+    }
+
+    let data = (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(InvalidLine)?;
+
+    Ok(Some((time, Frame {id, data})))
+}
+
+// Parse a frame read from a socket, as a `struct can_frame`, or a
+// `struct canfd_frame`, which differ only in their size. Remote and
+// error frames are skipped.
+fn parse_frame(buffer: &[u8]) -> Option<Frame> {
+    let id = u32::from_ne_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+    if id & (CAN_RTR_FLAG | CAN_ERR_FLAG) != 0 {
+        return None;
+    }
+
+    let id = if id & CAN_EFF_FLAG != 0 {id & CAN_EFF_MASK} else {id & CAN_SFF_MASK};
+    let length = (buffer[4] as usize).min(buffer.len() - 8);
+    Some(Frame {id, data: buffer[8..8 + length].to_vec()})
+}
+
+#[repr(C)]
+struct SockaddrCan {
+    family: libc::sa_family_t,
+    ifindex: libc::c_int,
+    // Transport protocol addresses, unused by raw sockets.
+    addr: [u32; 4]
+}
+
+// Open a raw socket on the named interface.
+fn open_interface(name: &str) -> io::Result<File> {
+    let name = CString::new(name)?;
+
+    unsafe {
+        let ifindex = libc::if_nametoindex(name.as_ptr());
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = libc::socket(AF_CAN, libc::SOCK_RAW, CAN_RAW);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Closes the socket if binding fails.
+        let socket = File::from_raw_fd(fd);
+
+        // Without this, CAN FD frames aren't delivered. Kernels too old
+        // to have them refuse it, and deliver classic frames as before.
+        let enable: libc::c_int = 1;
+        libc::setsockopt(
+            fd,
+            SOL_CAN_RAW,
+            CAN_RAW_FD_FRAMES,
+            &enable as *const libc::c_int as *const libc::c_void,
+            size_of::<libc::c_int>() as libc::socklen_t
+        );
+
+        let addr = SockaddrCan {
+            family: AF_CAN as libc::sa_family_t,
+            ifindex: ifindex as libc::c_int,
+            addr: [0; 4]
+        };
+
+        let result = libc::bind(
+            fd,
+            &addr as *const SockaddrCan as *const libc::sockaddr,
+            size_of::<SockaddrCan>() as libc::socklen_t
+        );
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+}
+
+pub enum Can {
+    // A SocketCAN interface, such as `can0` or `vcan0`.
+    Interface(String),
+    // A log written by `candump -l`.
+    Dump(String)
+}
+
+// Read frames from an interface, or play back a log.
+pub fn source(can: Can, config: &Config) -> io::Result<ThreadedSource> {
+    let decoder = Decoder::new(&config.can_signals);
+
+    match can {
+        Can::Interface(name) => {
+            let socket = open_interface(&name)?;
+            Ok(ThreadedSource::spawn(config, move |sink, input| {
+                read_interface(socket, &decoder, sink, input)
+            }))
+        },
+        Can::Dump(path) => {
+            let file = File::open(path)?;
+            Ok(ThreadedSource::spawn(config, move |sink, input| {
+                read_dump(file, &decoder, sink, input)
+            }))
+        }
+    }
+}
+
+// Merge the signals carried by a frame, if it carries any.
+fn merge_frame(frame: &Frame, time: Float, decoder: &Decoder, sink: &Shared) {
+    let values = decoder.decode(frame);
+    if !values.is_empty() {
+        sink.merge(Sample {values, time, received: sink.now()});
+    }
+}
+
+fn read_interface(mut socket: File, decoder: &Decoder, sink: &Shared, input: &Input) {
+    let mut buffer = [0; FD_FRAME_SIZE];
+
+    loop {
+        match socket.read(&mut buffer) {
+            Ok(n) if n == FRAME_SIZE || n == FD_FRAME_SIZE => {
+                if let Some(frame) = parse_frame(&buffer[..n]) {
+                    merge_frame(&frame, sink.now(), decoder, sink);
+                }
+            },
+            Ok(_) => sink.malformed(),
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading CAN interface: {}", e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+fn read_dump<R: Read>(src: R, decoder: &Decoder, sink: &Shared, input: &Input) {
+    // The first frame's recorded time, and the local time it was read.
+    let mut start = None;
+
+    let mut reader = BufReader::new(src);
+    loop {
+        let mut bytes = Vec::new();
+        match reader.read_until(b'\n', &mut bytes) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading CAN log: {}", e);
+                }
+                break;
+            }
+        }
+
+        let parsed = str::from_utf8(&bytes)
+            .map_err(|_| InvalidLine)
+            .and_then(parse_candump);
+        let (time, frame) = match parsed {
+            Ok(Some(frame)) => frame,
+            Ok(None) => continue,
+            Err(InvalidLine) => {
+                if input.log_errors {
+                    eprintln!("Not a CAN frame: {}", String::from_utf8_lossy(&bytes).trim());
+                }
+                sink.malformed();
+                continue;
+            }
+        };
+
+        let (first, then) = *start.get_or_insert((time, sink.now()));
+        let wait = (time - first) - (sink.now() - then);
+        if !(-MAX_GAP..=MAX_GAP).contains(&wait) {
+            // Play on from here, as though the gap hadn't happened.
+            sleep(Duration::from_millis((wait.clamp(0.0, MAX_GAP) * 1000.0) as u64));
+            start = Some((time, sink.now()));
+        } else if wait > 0.0 {
+            sleep(Duration::from_millis((wait * 1000.0) as u64));
+        }
+
+        merge_frame(&frame, time, decoder, sink);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signal(
+        start: u32,
+        length: u32,
+        byte_order: ByteOrder,
+        signed: bool
+    ) -> CanSignal {
+        CanSignal {
+            name: String::from("X"),
+            id: 0x123,
+            start,
+            length,
+            byte_order,
+            signed,
+            factor: 1.0,
+            offset: 0.0
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        let data = [0x12, 0x34, 0x56, 0x78, 0xff, 0xfe, 0x00, 0x80];
+        let little = |start, length, signed| {
+            decode(&signal(start, length, ByteOrder::LittleEndian, signed), &data)
+        };
+        let big = |start, length, signed| {
+            decode(&signal(start, length, ByteOrder::BigEndian, signed), &data)
+        };
+
+        assert_eq!(little(0, 16, false), Some(0x3412 as Float));
+        assert_eq!(little(4, 8, false), Some(0x41 as Float));
+        assert_eq!(little(32, 16, true), Some(-257.0));
+        assert_eq!(little(63, 1, false), Some(1.0));
+        assert_eq!(big(7, 16, false), Some(0x1234 as Float));
+        assert_eq!(big(3, 8, false), Some(0x23 as Float));
+        assert_eq!(big(39, 16, true), Some(-2.0));
+        assert_eq!(little(56, 16, false), None);
+
+        let rpm = CanSignal {factor: 0.25, offset: -10.0, ..signal(0, 16, ByteOrder::LittleEndian, false)};
+        assert_eq!(decode(&rpm, &[0x40, 0x1f]), Some(1990.0));
+
+        assert!(signal(7, 64, ByteOrder::BigEndian, false).is_valid());
+        assert!(!signal(510, 4, ByteOrder::LittleEndian, false).is_valid());
+        assert!(!signal(0, 0, ByteOrder::LittleEndian, false).is_valid());
+    }
+
+    #[test]
+    fn test_candump() {
+        assert_eq!(
+            parse_candump("(1571234567.250000) vcan0 123#DEADBEEF"),
+            Ok(Some((1571234567.25, Frame {id: 0x123, data: vec! {0xde, 0xad, 0xbe, 0xef}})))
+        );
+        assert_eq!(
+            parse_candump("(0.5) can1 18FEF100#"),
+            Ok(Some((0.5, Frame {id: 0x18fef100, data: Vec::new()})))
+        );
+        assert_eq!(
+            parse_candump("(0.5) can0 123##1AABB"),
+            Ok(Some((0.5, Frame {id: 0x123, data: vec! {0xaa, 0xbb}})))
+        );
+        assert_eq!(parse_candump("(0.5) can0 123#R"), Ok(None));
+        assert_eq!(parse_candump("  \n"), Ok(None));
+        assert_eq!(parse_candump("(0.5) can0 123#ABC"), Err(InvalidLine));
+        assert_eq!(parse_candump("(inf) can0 123#AB"), Err(InvalidLine));
+        assert_eq!(parse_candump("garbage"), Err(InvalidLine));
+        assert_eq!(parse_candump("(0.5) can0 123#AB\u{fffd}"), Err(InvalidLine));
+
+        let mut buffer = [0; FRAME_SIZE];
+        buffer[..4].copy_from_slice(&(0x18fef100 | CAN_EFF_FLAG).to_ne_bytes());
+        buffer[4] = 2;
+        buffer[8] = 0xaa;
+        buffer[9] = 0xbb;
+        assert_eq!(parse_frame(&buffer), Some(Frame {id: 0x18fef100, data: vec! {0xaa, 0xbb}}));
+
+        let mut buffer = [0xcc; FD_FRAME_SIZE];
+        buffer[..4].copy_from_slice(&0x123u32.to_ne_bytes());
+        buffer[4] = 64;
+        assert_eq!(parse_frame(&buffer), Some(Frame {id: 0x123, data: vec! {0xcc; 64}}));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut config = Config::empty();
+        config.can_signals = vec! {
+            CanSignal {name: String::from("RPM"), ..signal(0, 16, ByteOrder::LittleEndian, false)}
+        };
+
+        let mut log = b"(100.00) vcan0 123#E803\n\
+                        (100.01) vcan0 456#0000\n\
+                        (100.01) vcan0 123#\xff\xfe\n\
+                        not a frame\n".to_vec();
+        log.extend(b"(100.02) vcan0 123#D007\n");
+        let decoder = Decoder::new(&config.can_signals);
+        let sink = Shared::from_config(&config);
+        read_dump(&log[..], &decoder, &sink, &config.input);
+
+        let state = sink.snapshot(&[]);
+        assert_eq!(state.get(&String::from("RPM")), Some(2000.0));
+        assert_eq!(state.time, 100.02);
+        assert_eq!(sink.counters().merged(), 2);
+        assert_eq!(sink.counters().malformed(), 2);
+
+        // An hour passes between these frames, but not in playback.
+        let log = "(100.00) vcan0 123#E803\n\
+                   (3700.00) vcan0 123#D007\n";
+        let sink = Shared::from_config(&config);
+        read_dump(log.as_bytes(), &decoder, &sink, &config.input);
+        assert_eq!(sink.counters().merged(), 2);
+        assert!(sink.now() < 2.0 * MAX_GAP);
+    }
+}
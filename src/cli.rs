@@ -0,0 +1,345 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Command line handling
+//
+// Shared by `udashboard` and `preview`, which take a config file, and
+// possibly a device, followed by options selecting the data source.
+
+use std::io::{self, stdin};
+use std::net::IpAddr;
+
+use crate::can::{self, Can};
+use crate::config::{Config, Float, InputFormat};
+use crate::gps;
+use crate::data::{read_source, DataSource};
+use crate::net::{Tcp, TcpSource, UdpSource};
+use crate::obd;
+use crate::racecapture;
+use crate::replay::ReplaySource;
+use crate::simulate;
+use crate::unix;
+
+pub const USAGE: &str = "\
+Options:
+  --replay <file>   Play back a log, instead of reading stdin. Playback
+                    is controlled from stdin: pause, resume,
+                    seek <seconds>, speed <multiplier>, loop on|off.
+  --speed <x>       Replay at x times real time.
+  --loop            Start the replay over at the end of the log.
+  --simulate <spec> Synthesize data from a simulation spec, instead of
+                    reading stdin.
+  --udp <addr>      Read datagrams sent to the given address and port.
+  --tcp-connect <addr>
+                    Read from a TCP connection to the given address,
+                    reconnecting whenever it drops.
+  --tcp-listen <addr>
+                    Read from TCP connections accepted on the given
+                    address, one at a time.
+  --allow <ip>      Only read from the given peer address. May be given
+                    more than once.
+  --unix <path>     Read from any number of producers connecting to a
+                    Unix socket at the given path.
+  --can <interface> Decode CAN signals from a SocketCAN interface.
+  --candump <file>  Decode CAN signals from a log written by candump -l.
+  --obd <device>    Poll an ELM327 OBD-II adapter on a serial device.
+  --racecapture <device or host:port>
+                    Stream telemetry from a RaceCapture unit, over USB
+                    serial or TCP.
+  --rate <hz>       RaceCapture telemetry rate, 50 by default.
+  --gps <path>      Read NMEA sentences from a GPS receiver's serial
+                    device, or play them back from a file.
+  --format <format> Format of samples read from stdin or TCP: json,
+                    binary, msgpack, cbor, or auto, the default, to
+                    detect it from the first byte.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    // JSON samples on stdin.
+    Stdin,
+    Replay {path: String, speed: Float, looping: bool},
+    // Synthesized samples, from the spec at the given path.
+    Simulate(String),
+    Udp {bind: String, allow: Vec<IpAddr>},
+    TcpConnect {addr: String, allow: Vec<IpAddr>},
+    TcpListen {addr: String, allow: Vec<IpAddr>},
+    // Producers connecting to a Unix socket at the given path.
+    Unix(String),
+    // A SocketCAN interface.
+    Can(String),
+    // A candump log file.
+    Candump(String),
+    // An ELM327 adapter on a serial device.
+    Obd(String),
+    // A RaceCapture unit, by serial device or TCP address.
+    RaceCapture {addr: String, rate: u32},
+    // A GPS receiver's serial device, or a file of NMEA sentences.
+    Gps(String)
+}
+
+impl Source {
+    pub fn open(&self, config: &Config) -> io::Result<Box<dyn DataSource>> {
+        match self {
+            Source::Stdin => Ok(Box::new(read_source(stdin(), config))),
+            Source::Replay {path, speed, looping} => {
+                let source = ReplaySource::new(path, config, *speed, *looping)?;
+                source.read_commands(stdin());
+                Ok(Box::new(source))
+            },
+            Source::Simulate(path) => {
+                let simulation = simulate::load(path)?;
+                Ok(Box::new(simulate::source(simulation, config)))
+            },
+            Source::Udp {bind, allow} =>
+                Ok(Box::new(UdpSource::new(bind, allow.clone(), config)?)),
+            Source::TcpConnect {addr, allow} => Ok(Box::new(TcpSource::new(
+                Tcp::Connect(addr.clone()),
+                allow.clone(),
+                config
+            )?)),
+            Source::TcpListen {addr, allow} => Ok(Box::new(TcpSource::new(
+                Tcp::Listen(addr.clone()),
+                allow.clone(),
+                config
+            )?)),
+            Source::Unix(path) => Ok(Box::new(unix::source(path, config)?)),
+            Source::Can(interface) => Ok(Box::new(
+                can::source(Can::Interface(interface.clone()), config)?
+            )),
+            Source::Candump(path) => Ok(Box::new(
+                can::source(Can::Dump(path.clone()), config)?
+            )),
+            Source::Obd(device) => Ok(Box::new(obd::source(device, config)?)),
+            Source::RaceCapture {addr, rate} =>
+                Ok(Box::new(racecapture::source(addr, *rate, config))),
+            Source::Gps(path) => Ok(Box::new(gps::source(path, config)?))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub config: String,
+    // Arguments after the config file which aren't options.
+    pub args: Vec<String>,
+    pub source: Source,
+    // Overrides the input format given in the config file.
+    pub format: Option<InputFormat>
+}
+
+// Parse the arguments which follow the program name.
+pub fn parse<I>(args: I) -> Result<Options, String>
+where I: IntoIterator<Item = String> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut sources = Vec::new();
+    let mut speed = 1.0;
+    let mut looping = false;
+    let mut allow = Vec::new();
+    let mut rate = None;
+    let mut format = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--replay" => sources.push(Source::Replay {
+                path: args.next().ok_or("--replay needs a file")?,
+                speed,
+                looping
+            }),
+            "--speed" => speed = args
+                .next()
+                .and_then(|x| x.parse().ok())
+                .filter(|x: &Float| *x > 0.0)
+                .ok_or("--speed needs a positive number")?,
+            "--loop" => looping = true,
+            "--simulate" => sources.push(Source::Simulate(
+                args.next().ok_or("--simulate needs a spec")?
+            )),
+            "--udp" => sources.push(Source::Udp {
+                bind: args.next().ok_or("--udp needs an address")?,
+                allow: Vec::new()
+            }),
+            "--tcp-connect" => sources.push(Source::TcpConnect {
+                addr: args.next().ok_or("--tcp-connect needs an address")?,
+                allow: Vec::new()
+            }),
+            "--tcp-listen" => sources.push(Source::TcpListen {
+                addr: args.next().ok_or("--tcp-listen needs an address")?,
+                allow: Vec::new()
+            }),
+            "--unix" => sources.push(Source::Unix(
+                args.next().ok_or("--unix needs a path")?
+            )),
+            "--can" => sources.push(Source::Can(
+                args.next().ok_or("--can needs an interface")?
+            )),
+            "--candump" => sources.push(Source::Candump(
+                args.next().ok_or("--candump needs a file")?
+            )),
+            "--obd" => sources.push(Source::Obd(
+                args.next().ok_or("--obd needs a device")?
+            )),
+            "--racecapture" => sources.push(Source::RaceCapture {
+                addr: args.next().ok_or("--racecapture needs a device or address")?,
+                rate: 50
+            }),
+            "--rate" => rate = Some(args
+                .next()
+                .and_then(|x| x.parse().ok())
+                .filter(|x: &u32| *x > 0)
+                .ok_or("--rate needs a positive whole number")?),
+            "--gps" => sources.push(Source::Gps(
+                args.next().ok_or("--gps needs a device or file")?
+            )),
+            "--format" => format = match args.next().as_deref() {
+                Some("json") => Some(InputFormat::Json),
+                Some("binary") => Some(InputFormat::Binary),
+                Some("msgpack") => Some(InputFormat::MessagePack),
+                Some("cbor") => Some(InputFormat::Cbor),
+                Some("auto") => Some(InputFormat::Auto),
+                _ => return Err(String::from("--format needs json, binary, msgpack, cbor or auto"))
+            },
+            "--allow" => allow.push(
+                args.next()
+                    .and_then(|x| x.parse().ok())
+                    .ok_or("--allow needs an IP address")?
+            ),
+            _ if arg.starts_with("--") =>
+                return Err(format!("Unknown option: {}", arg)),
+            _ => positional.push(arg)
+        }
+    }
+
+    if positional.is_empty() {
+        return Err(String::from("No config file given"));
+    }
+
+    let config = positional.remove(0);
+    if sources.len() > 1 {
+        return Err(String::from("Only one data source may be given"));
+    }
+
+    // Options which modify a source apply wherever they appear, but
+    // only to sources which use them.
+    let networked = matches!(
+        sources.last(),
+        Some(Source::Udp {..}) | Some(Source::TcpConnect {..}) | Some(Source::TcpListen {..})
+    );
+    if !networked && !allow.is_empty() {
+        return Err(String::from("--allow needs --udp, --tcp-connect or --tcp-listen"));
+    }
+    let racecapture = matches!(sources.last(), Some(Source::RaceCapture {..}));
+    if !racecapture && rate.is_some() {
+        return Err(String::from("--rate needs --racecapture"));
+    }
+
+    let source = match sources.pop() {
+        Some(Source::Replay {path, ..}) => Source::Replay {path, speed, looping},
+        Some(Source::Udp {bind, ..}) => Source::Udp {bind, allow},
+        Some(Source::TcpConnect {addr, ..}) => Source::TcpConnect {addr, allow},
+        Some(Source::TcpListen {addr, ..}) => Source::TcpListen {addr, allow},
+        Some(Source::RaceCapture {addr, rate: default}) =>
+            Source::RaceCapture {addr, rate: rate.unwrap_or(default)},
+        Some(source) => source,
+        None => Source::Stdin
+    };
+
+    Ok(Options {config, args: positional, source, format})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(args(&["demo.ron", "/dev/dri/card0"])), Ok(Options {
+            config: String::from("demo.ron"),
+            args: args(&["/dev/dri/card0"]),
+            source: Source::Stdin,
+            format: None
+        }));
+
+        assert_eq!(parse(args(&["--replay", "log.jsonl", "demo.ron", "--loop"])), Ok(Options {
+            config: String::from("demo.ron"),
+            args: Vec::new(),
+            source: Source::Replay {
+                path: String::from("log.jsonl"),
+                speed: 1.0,
+                looping: true
+            },
+            format: None
+        }));
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--simulate", "spec.ron"])).map(|o| o.source),
+            Ok(Source::Simulate(String::from("spec.ron")))
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--allow", "10.0.0.2", "--udp", "0.0.0.0:9000"]))
+                .map(|o| o.source),
+            Ok(Source::Udp {
+                bind: String::from("0.0.0.0:9000"),
+                allow: vec! {"10.0.0.2".parse().unwrap()}
+            })
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--unix", "/run/udashboard.sock"])).map(|o| o.source),
+            Ok(Source::Unix(String::from("/run/udashboard.sock")))
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--can", "vcan0"])).map(|o| o.source),
+            Ok(Source::Can(String::from("vcan0")))
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--racecapture", "192.168.4.1:7223", "--rate", "25"]))
+                .map(|o| o.source),
+            Ok(Source::RaceCapture {addr: String::from("192.168.4.1:7223"), rate: 25})
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--format", "binary"])).map(|o| o.format),
+            Ok(Some(InputFormat::Binary))
+        );
+
+        assert_eq!(
+            parse(args(&["demo.ron", "--format", "cbor"])).map(|o| o.format),
+            Ok(Some(InputFormat::Cbor))
+        );
+
+        assert!(parse(args(&["demo.ron", "--speed", "0"])).is_err());
+        assert!(parse(args(&["demo.ron", "--format", "xml"])).is_err());
+        assert!(parse(args(&["demo.ron", "--allow", "somewhere"])).is_err());
+        assert!(parse(args(&["demo.ron", "--allow", "10.0.0.2"])).is_err());
+        assert!(parse(args(&["demo.ron", "--unix", "a", "--allow", "10.0.0.2"])).is_err());
+        assert!(parse(args(&["demo.ron", "--gps", "a", "--rate", "25"])).is_err());
+        assert!(parse(args(&["demo.ron", "--udp", "a", "--tcp-listen", "b"])).is_err());
+        assert!(parse(args(&["demo.ron", "--replay", "a", "--simulate", "b"])).is_err());
+        assert!(parse(args(&["demo.ron", "--bogus"])).is_err());
+        assert!(parse(args(&[])).is_err());
+    }
+}
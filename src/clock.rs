@@ -19,6 +19,7 @@
 use std::time::Instant;
 
 // Wrapper around somewhat obnoxious system time api.
+#[derive(Copy, Clone, Debug)]
 pub struct Clock {
     instant: Instant,
 }
@@ -25,6 +25,8 @@ use std::{
 
 use serde::{Deserialize};
 
+use crate::ast::Expr;
+
 pub type Float = f64;
 
 #[derive(Deserialize, Debug, Clone)]
@@ -106,8 +108,34 @@ pub enum GaugeStyle {
     Dashed
 }
 
+// Marks the extremes a gauge's channel has reached this session.
+#[derive(Deserialize, Debug, Copy, Clone)]
+pub enum PeakHold {
+    Max,
+    Min,
+    Both
+}
+
+impl PeakHold {
+    // The pseudo-channels holding the marked values.
+    pub fn suffixes(&self) -> &'static [&'static str] {
+        match self {
+            PeakHold::Max => &["max"],
+            PeakHold::Min => &["min"],
+            PeakHold::Both => &["min", "max"]
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
-pub struct Scale(pub Float, pub Float, pub Divisions, pub GaugeStyle);
+pub struct Scale(
+    pub Float,
+    pub Float,
+    pub Divisions,
+    pub GaugeStyle,
+    #[serde(default)]
+    pub Option<PeakHold>
+);
 
 impl Scale {
     pub fn range(&self) -> Float {
@@ -151,7 +179,9 @@ pub enum Unit {
 #[derive(Deserialize, Debug, Hash, Clone, PartialEq, Eq)]
 pub enum State {
     Default,
-    Alarm(String)
+    Alarm(String),
+    // The named channel has not been updated within its timeout.
+    Stale(String)
 }
 
 #[derive(Deserialize, Debug, Copy, Clone)]
@@ -164,8 +194,95 @@ pub enum Test {
     Between(Float, Float)
 }
 
+impl Test {
+    // Apply the test to the value of a channel, if any.
+    //
+    // A channel without a value never satisfies a test, except `Always`.
+    pub fn check(&self, value: Option<Float>) -> bool {
+        match (self, value) {
+            (Test::Always, _)                    => true,
+            (Test::Never, _)                     => false,
+            (_, None)                            => false,
+            (Test::LessThan(x), Some(v))         => v < *x,
+            (Test::GreaterThan(x), Some(v))      => v > *x,
+            (Test::Equal(x), Some(v))            => v == *x,
+            (Test::Between(lo, hi), Some(v))     => *lo <= v && v <= *hi
+        }
+    }
+
+    // Return a copy of this test, with its thresholds relaxed by the
+    // given amount.
+    pub fn widen(&self, band: Float) -> Test {
+        match self {
+            Test::LessThan(x)      => Test::LessThan(x + band),
+            Test::GreaterThan(x)   => Test::GreaterThan(x - band),
+            Test::Equal(x)         => Test::Between(x - band, x + band),
+            Test::Between(lo, hi)  => Test::Between(lo - band, hi + band),
+            test                   => *test
+        }
+    }
+}
+
+// A logical assertion about the current data.
+//
+// Conditions may refer to other conditions by name, via `Cond`. The
+// loader guarantees these references are defined and acyclic.
+#[derive(Deserialize, Debug, Clone)]
+pub enum Condition {
+    Always,
+    Never,
+    // Test the value of a single channel.
+    Is(String, Test),
+    // True if the first channel lies within the envelope defined by
+    // applying the lower and upper functions to the second channel.
+    Within(String, String, Function, Function),
+    // The state of another named condition.
+    Cond(String),
+    Not(Box<Condition>),
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    // True if an odd number of the operands are true.
+    Xor(Vec<Condition>),
+    // Like `Is`, but once true, stays true until the value moves
+    // past the threshold by more than the given band.
+    Hysteresis(String, Test, Float),
+    // Becomes true only once the operand has been true for the first
+    // duration (in seconds), and becomes false only once it has been
+    // false for the second.
+    Debounce(Box<Condition>, Float, Float),
+}
+
+impl Condition {
+    // Collect the names of all conditions referenced by this one.
+    pub fn references(&self, names: &mut Vec<String>) {
+        match self {
+            Condition::Cond(name) => names.push(name.clone()),
+            Condition::Not(c) |
+            Condition::Debounce(c, _, _) => c.references(names),
+            Condition::And(cs) |
+            Condition::Or(cs)  |
+            Condition::Xor(cs) => for c in cs { c.references(names) },
+            _ => ()
+        }
+    }
+
+    // Check that every function used by this condition is valid.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Condition::Within(_, _, lower, upper) =>
+                lower.is_valid() && upper.is_valid(),
+            Condition::Not(c) |
+            Condition::Debounce(c, _, _) => c.is_valid(),
+            Condition::And(cs) |
+            Condition::Or(cs)  |
+            Condition::Xor(cs) => cs.iter().all(|c| c.is_valid()),
+            _ => true
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
-pub struct When(String, Test, State);
+pub struct When(pub Condition, pub State);
 
 pub type Logic = Vec<When>;
 
@@ -229,6 +346,37 @@ pub enum Pattern {
     FastBlink(Color),
 }
 
+// Timing of a blinking pattern.
+#[derive(Deserialize, Debug, Copy, Clone)]
+pub struct Blink {
+    // Duration of one on-off cycle, in seconds.
+    pub period: Float,
+    // Fraction of the period for which the pattern is shown.
+    pub duty: Float
+}
+
+impl Blink {
+    pub fn slow() -> Blink {
+        Blink {period: 1.0, duty: 0.5}
+    }
+
+    pub fn fast() -> Blink {
+        Blink {period: 0.25, duty: 0.5}
+    }
+
+    // Return whether the pattern is shown at the given time.
+    //
+    // Every cycle starts at a multiple of the period, so all patterns
+    // with the same timing blink in phase.
+    pub fn is_lit(&self, time: Float) -> bool {
+        if self.period > 0.0 {
+            (time / self.period).fract() < self.duty
+        } else {
+            true
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Copy, Clone)]
 pub struct Style {
     pub background: Pattern,
@@ -259,24 +407,385 @@ pub struct Gauge {
     pub styles: StyleSet
 }
 
+// What a lookup table does with inputs outside its range.
+#[derive(Deserialize, Debug, Copy, Clone)]
+pub enum OutOfRange {
+    // Return the value at the nearest end of the table.
+    Clamp,
+    // Continue the slope of the nearest segment of the table.
+    Extrapolate
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub enum Function {
     Identity,
     Scale(Float),
+    // Slope, then offset.
     Linear(Float, Float),
-    Polynomial(Vec<Float>)
+    // Coefficients in order of increasing degree.
+    Polynomial(Vec<Float>),
+    // Linear interpolation between (input, output) pairs, sorted by
+    // increasing input.
+    Table(Vec<(Float, Float)>, OutOfRange),
+    // Resistance of the lower leg of a voltage divider, given its
+    // output voltage, the resistance of the upper leg, and the supply
+    // voltage.
+    Divider(Float, Float),
+    // Temperature in Celsius of an NTC thermistor, given its
+    // resistance, according to the Steinhart-Hart equation with the
+    // coefficients A, B and C.
+    Thermistor(Float, Float, Float),
+    // Apply each function in turn to the result of the previous one.
+    Chain(Vec<Function>)
+}
+
+impl Function {
+    pub fn apply(&self, x: Float) -> Float {
+        match self {
+            Function::Identity       => x,
+            Function::Scale(k)       => k * x,
+            Function::Linear(m, b)   => m * x + b,
+            Function::Polynomial(cs) => cs
+                .iter()
+                .rev()
+                .fold(0.0, |acc, c| acc * x + c),
+            Function::Table(points, out_of_range) =>
+                Self::interpolate(points, *out_of_range, x),
+            Function::Divider(upper, supply) => upper * x / (supply - x),
+            Function::Thermistor(a, b, c) => {
+                let ln_r = x.ln();
+                1.0 / (a + b * ln_r + c * ln_r.powi(3)) - 273.15
+            },
+            Function::Chain(fs) => fs.iter().fold(x, |acc, f| f.apply(acc))
+        }
+    }
+
+    // Check that every table is sorted and has at least two points.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Function::Table(points, _) => points.len() >= 2 && points
+                .windows(2)
+                .all(|w| w[0].0 < w[1].0),
+            Function::Chain(fs) => fs.iter().all(|f| f.is_valid()),
+            _ => true
+        }
+    }
+
+    fn interpolate(
+        points: &[(Float, Float)],
+        out_of_range: OutOfRange,
+        x: Float
+    ) -> Float {
+        let last = points.len() - 1;
+        let (first_x, first_y) = points[0];
+        let (last_x, last_y) = points[last];
+
+        let segment = match out_of_range {
+            OutOfRange::Clamp if x <= first_x => return first_y,
+            OutOfRange::Clamp if x >= last_x => return last_y,
+            _ if x <= first_x => 1,
+            _ => points
+                .iter()
+                .position(|p| p.0 >= x)
+                .unwrap_or(last)
+                .max(1)
+        };
+
+        let (x0, y0) = points[segment - 1];
+        let (x1, y1) = points[segment];
+        y0 + (x - x0) * (y1 - y0) / (x1 - x0)
+    }
+}
+
+// Smooths a channel's values over time.
+#[derive(Deserialize, Debug, Copy, Clone)]
+pub enum Filter {
+    // Exponential low-pass, with the given time constant in seconds.
+    LowPass(Float),
+    // Mean of the last N samples.
+    MovingAverage(usize),
+    // Median of the last N samples, which rejects isolated spikes.
+    Median(usize),
+    // Limit the rate of change to the given units per second.
+    RateLimit(Float)
+}
+
+impl Filter {
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Filter::LowPass(tau) => *tau > 0.0,
+            Filter::MovingAverage(n) | Filter::Median(n) => *n > 0,
+            Filter::RateLimit(rate) => *rate > 0.0
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Channel {
     pub name: String,
-    pub units: Unit
+    pub units: Unit,
+    // Seconds without an update after which the channel is stale.
+    pub timeout: Option<Float>,
+    // Key of the input value this channel reads, if not its name.
+    pub input: Option<String>,
+    // Function converting the input value to the channel value.
+    pub calibration: Option<Function>,
+    // Filters applied in turn to the calibrated value. The unfiltered
+    // value is kept under the channel name with a `.raw` suffix.
+    pub filters: Option<Vec<Filter>>
+}
+
+// Names the key in each input sample which holds its timestamp.
+#[derive(Deserialize, Debug, Clone)]
+pub enum Timestamp {
+    Seconds(String),
+    Milliseconds(String)
+}
+
+impl Timestamp {
+    pub fn key(&self) -> &String {
+        match self {
+            Timestamp::Seconds(key) => key,
+            Timestamp::Milliseconds(key) => key
+        }
+    }
+
+    pub fn to_seconds(&self, value: Float) -> Float {
+        match self {
+            Timestamp::Seconds(_) => value,
+            Timestamp::Milliseconds(_) => value / 1000.0
+        }
+    }
+}
+
+// A channel whose value is computed from other channels.
+#[derive(Debug, Clone)]
+pub struct Derived {
+    pub name: String,
+    pub units: Unit,
+    pub expr: Expr
 }
 
+// Computes a channel from the history of the named channel.
 #[derive(Deserialize, Debug, Clone)]
+pub enum TimeFunction {
+    // Rate of change, per second.
+    Derivative(String),
+    // Running total, in units times seconds.
+    Integral(String)
+}
+
+impl TimeFunction {
+    pub fn source(&self) -> &String {
+        match self {
+            TimeFunction::Derivative(name) => name,
+            TimeFunction::Integral(name) => name
+        }
+    }
+}
+
+// A channel computed over time from another, as samples arrive.
+#[derive(Deserialize, Debug, Clone)]
+pub struct TimeChannel {
+    pub name: String,
+    pub units: Unit,
+    pub function: TimeFunction,
+    // Factor applied to the result, e.g. to convert cc/min to cc/s.
+    pub scale: Option<Float>,
+    // Channel which holds the value at zero while it is non-zero.
+    pub reset: Option<String>,
+    // File in which an integral's running total is kept, so that it
+    // survives a restart.
+    pub persist: Option<String>
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum ByteOrder {
+    // Intel byte order, in which `start` is the least significant bit.
+    LittleEndian,
+    // Motorola byte order, in which `start` is the most significant bit.
+    BigEndian
+}
+
+// A signal carried in CAN frames, as declared in a DBC file.
+//
+// Bits are numbered from the least significant bit of the first byte
+// of the frame, so bit 8 is the least significant bit of the second.
+#[derive(Deserialize, Debug, Clone)]
+pub struct CanSignal {
+    // Input key under which the signal's value is given.
+    pub name: String,
+    // Identifier of the frames which carry the signal.
+    pub id: u32,
+    pub start: u32,
+    // Length of the signal in bits.
+    pub length: u32,
+    pub byte_order: ByteOrder,
+    pub signed: bool,
+    // The signal's value is its raw value times `factor`, plus `offset`.
+    pub factor: Float,
+    pub offset: Float
+}
+
+impl CanSignal {
+    // A signal must fit within a 64-byte frame, and a 64-bit integer.
+    pub fn is_valid(&self) -> bool {
+        let (start, length) = (self.start, self.length);
+        let fits = match self.byte_order {
+            ByteOrder::LittleEndian => start + length <= 512,
+            // From the start bit down to the end of its byte, then whole
+            // bytes to the end of the frame.
+            ByteOrder::BigEndian => start < 512 &&
+                length <= start % 8 + 1 + 8 * (63 - start / 8)
+        };
+
+        (1..=64).contains(&length) && fits && self.factor.is_finite()
+    }
+}
+
+// Standard OBD-II mode 01 parameters.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Pid {
+    EngineLoad,
+    CoolantTemp,
+    ShortFuelTrim,
+    LongFuelTrim,
+    FuelPressure,
+    IntakePressure,
+    Rpm,
+    Speed,
+    TimingAdvance,
+    IntakeTemp,
+    MafRate,
+    ThrottlePosition,
+    RunTime,
+    FuelLevel,
+    BarometricPressure,
+    ControlModuleVoltage,
+    AmbientTemp,
+    OilTemp,
+    FuelRate
+}
+
+// Options for polling an ELM327 OBD-II adapter.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Obd {
+    // Parameters to poll, in turn.
+    pub pids: Vec<Pid>,
+    // Requests per second, across all parameters, 10 by default.
+    pub rate: Option<Float>,
+    // Seconds to wait for a response, 5 by default.
+    pub timeout: Option<Float>,
+    // Serial line speed, 38400 by default.
+    pub baud: Option<u32>
+}
+
+impl Obd {
+    pub fn is_valid(&self) -> bool {
+        let positive = |x: Option<Float>| match x {
+            Some(x) => x > 0.0,
+            None => true
+        };
+        let baud = match self.baud {
+            Some(baud) => [9600, 19200, 38400, 57600, 115_200, 230_400].contains(&baud),
+            None => true
+        };
+
+        !self.pids.is_empty() && positive(self.rate) && positive(self.timeout) && baud
+    }
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    // One JSON map per line.
+    Lines,
+    Csv
+}
+
+// Options for logging samples to disk.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Log {
+    pub directory: String,
+    pub formats: Vec<LogFormat>,
+    // Seconds between syncing the log to disk, one by default.
+    pub sync_interval: Option<Float>,
+    // Bytes after which a log file is continued in a new one.
+    pub max_size: Option<u64>
+}
+
+impl Log {
+    pub fn is_valid(&self) -> bool {
+        match self.sync_interval {
+            Some(interval) => interval.is_finite() && interval > 0.0,
+            None => true
+        }
+    }
+}
+
+// Encoding of the samples in a stream.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum InputFormat {
+    // One JSON map per line.
+    Json,
+    // Binary frames, as described in `binary.rs`.
+    Binary,
+    // A MessagePack map per sample.
+    MessagePack,
+    // A CBOR map per sample.
+    Cbor,
+    // Any of the above, detected from the first byte of the stream.
+    Auto
+}
+
+// Options for reading samples from a stream.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Input {
+    pub timestamp: Option<Timestamp>,
+    // Print input errors, and the offending input, to stderr.
+    pub log_errors: bool,
+    pub format: InputFormat
+}
+
+#[derive(Debug, Clone)]
 pub struct Config {
     pub screen: Screen,
+    pub frame_rate: Float,
+    pub slow_blink: Blink,
+    pub fast_blink: Blink,
     pub channels: Vec<Channel>,
+    pub derived: Vec<Derived>,
+    pub time_channels: Vec<TimeChannel>,
+    // Channel which clears the session statistics while it is non-zero.
+    pub stats_reset: Option<String>,
     pub pages: Vec<Vec<Gauge>>,
     pub logic: Logic,
+    pub input: Input,
+    pub log: Option<Log>,
+    // Signals decoded from CAN frames.
+    pub can_signals: Vec<CanSignal>,
+    pub obd: Option<Obd>
+}
+
+impl Config {
+    // A config with no channels, pages, or rules, for testing data
+    // sources.
+    #[cfg(test)]
+    pub fn empty() -> Config {
+        Config {
+            screen: Screen {width: 800.0, height: 480.0},
+            frame_rate: 30.0,
+            slow_blink: Blink::slow(),
+            fast_blink: Blink::fast(),
+            channels: Vec::new(),
+            derived: Vec::new(),
+            time_channels: Vec::new(),
+            stats_reset: None,
+            pages: Vec::new(),
+            logic: Vec::new(),
+            input: Input {timestamp: None, log_errors: false, format: InputFormat::Auto},
+            log: None,
+            can_signals: Vec::new(),
+            obd: None
+        }
+    }
 }
@@ -19,48 +19,226 @@
 // Data handling
 
 use std::{
-    cell::RefCell,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{
         BufReader,
         BufRead,
+        Cursor,
         Read
     },
-    sync::{mpsc::{sync_channel, Receiver, TrySendError}},
+    str,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+        mpsc::Sender
+    },
     thread::{spawn}
 };
 
 use serde_json;
+use serde_json::Value;
 
-use crate::config::{Float};
+use crate::binary;
+use crate::packed::{self, Encoding};
+use crate::clock::Clock;
+use crate::logger::Logger;
+use crate::config::{Channel, Config, Float, Input, InputFormat, Timestamp};
+use crate::pipeline::Pipeline;
 
 #[derive(Debug, Clone)]
 pub struct State {
     pub values: HashMap<String, Float>,
     pub states: HashMap<String, bool>,
-    pub time: Float
+    // Time of the most recent sample.
+    pub time: Float,
+    // Time of the most recent sample to include each channel.
+    pub updated: HashMap<String, Float>,
+    // Local clock time at which the most recent sample was received.
+    pub received: Float,
+    // Channels which have not been updated within their timeout.
+    pub stale: HashSet<String>,
+    // The data source has ended, and no more samples will arrive.
+    pub source_lost: bool,
+    // Statistics of each channel since startup, or since the last reset.
+    pub summaries: HashMap<String, Summary>,
+    // Producer which last updated each channel, for sources which merge
+    // samples from several producers.
+    pub producers: HashMap<String, String>,
+    // Producers which have disconnected. Their channels are stale until
+    // they are updated again.
+    pub disconnected: HashSet<String>,
+    // Channel which clears the statistics while it is non-zero.
+    stats_reset: Option<String>
+}
+
+// Running statistics of a channel's values.
+#[derive(Debug, Copy, Clone)]
+pub struct Summary {
+    pub min: Float,
+    pub max: Float,
+    pub total: Float,
+    pub count: usize
+}
+
+impl Summary {
+    fn new(value: Float) -> Summary {
+        Summary {min: value, max: value, total: value, count: 1}
+    }
+
+    fn add(&mut self, value: Float) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.total += value;
+        self.count += 1;
+    }
+
+    pub fn mean(&self) -> Float {
+        self.total / self.count as Float
+    }
 }
 
+#[derive(Debug, Clone)]
 pub struct Sample {
     pub values: HashMap<String, Float>,
-    pub time: Float
+    pub time: Float,
+    // Local clock time at which the sample was received.
+    pub received: Float
 }
 
 impl State {
     pub fn new() -> State {
+        State::with_stats_reset(None)
+    }
+
+    // Create a state which summarizes channels as configured.
+    pub fn from_config(config: &Config) -> State {
+        State::with_stats_reset(config.stats_reset.clone())
+    }
+
+    fn with_stats_reset(stats_reset: Option<String>) -> State {
         State {
             values: HashMap::new(),
             states: HashMap::new(),
-            time: 0.0
+            time: 0.0,
+            updated: HashMap::new(),
+            received: 0.0,
+            stale: HashSet::new(),
+            source_lost: false,
+            summaries: HashMap::new(),
+            producers: HashMap::new(),
+            disconnected: HashSet::new(),
+            stats_reset
         }
     }
 
+    // Merge a sample of channel values, which need no processing.
     pub fn update(
         &mut self,
         sample: Sample
     ) {
-        self.values.extend(sample.values);
+        self.apply(&mut Pipeline::default(), &sample);
+    }
+
+    // Attribute the given channels to the named producer, which last
+    // updated them.
+    fn attribute(&mut self, producer: &str, channels: Vec<String>) {
+        for key in channels {
+            self.producers.insert(key, String::from(producer));
+        }
+        self.disconnected.remove(producer);
+    }
+
+    // Record that the named producer has disconnected.
+    pub fn disconnect(&mut self, producer: &str) {
+        self.disconnected.insert(String::from(producer));
+    }
+
+    // Merge a sample, converted to channel values by the given
+    // pipeline, and return the channels it updated.
+    fn apply(&mut self, pipeline: &mut Pipeline, sample: &Sample) -> Vec<String> {
+        let values = pipeline.process(&sample.values, sample.time);
+        let mut changed: Vec<String> = values.keys().cloned().collect();
+        for (key, value) in &values {
+            self.values.insert(key.clone(), *value);
+        }
+        changed.extend(pipeline.integrate(
+            &values,
+            &mut self.values,
+            sample.time
+        ));
+        changed.extend(pipeline.derive(&mut self.values));
+        self.summarize(&changed);
+
+        for key in &changed {
+            self.updated.insert(key.clone(), sample.time);
+        }
         self.time = sample.time;
+        self.received = sample.received;
+        changed
+    }
+
+    // Add the latest values of the given channels to their statistics,
+    // which are published as the pseudo-channels `<name>.min`,
+    // `<name>.max` and `<name>.mean`.
+    //
+    // Pseudo-channels, whose names contain a `.`, are not summarized.
+    fn summarize(&mut self, changed: &[String]) {
+        let reset = match &self.stats_reset {
+            Some(name) => self.get(name).unwrap_or(0.0) != 0.0,
+            None => false
+        };
+
+        if reset {
+            for name in self.summaries.keys() {
+                for suffix in &["min", "max", "mean"] {
+                    self.values.remove(&format!("{}.{}", name, suffix));
+                }
+            }
+            self.summaries.clear();
+            return;
+        }
+
+        for name in changed.iter().filter(|name| !name.contains('.')) {
+            let value = self.values[name];
+            let summary = self.summaries
+                .entry(name.clone())
+                .and_modify(|s| s.add(value))
+                .or_insert_with(|| Summary::new(value));
+
+            let summary = *summary;
+            self.values.insert(format!("{}.min", name), summary.min);
+            self.values.insert(format!("{}.max", name), summary.max);
+            self.values.insert(format!("{}.mean", name), summary.mean());
+        }
+    }
+
+    // Flag the channels whose timeout has elapsed since their last
+    // update, as of the given local clock time, and those last updated
+    // by a producer which has since disconnected.
+    //
+    // Sample times need not come from the local clock, so the current
+    // sample time is estimated from the time elapsed since the last
+    // sample was received.
+    pub fn check_stale(&mut self, channels: &[Channel], now: Float) {
+        let now = self.time + (now - self.received);
+        let updated = &self.updated;
+        let lost = |name: &String| match self.producers.get(name) {
+            Some(producer) => self.disconnected.contains(producer),
+            None => false
+        };
+
+        let stale = channels
+            .iter()
+            .filter(|c| lost(&c.name) || match (c.timeout, updated.get(&c.name)) {
+                (None, _)                => false,
+                (Some(_), None)          => true,
+                (Some(timeout), Some(t)) => now - t > timeout
+            })
+            .map(|c| c.name.clone())
+            .collect();
+
+        self.stale = stale;
     }
 
     pub fn get(&self, key: &String) -> Option<Float> {
@@ -76,47 +254,493 @@ pub trait DataSource {
     fn get_state(&self) -> State;
 }
 
+impl<T> DataSource for Box<T> where T: DataSource + ?Sized {
+    fn get_state(&self) -> State {
+        (**self).get_state()
+    }
+}
+
 
-pub struct ReadSource {
-    receiver: Receiver<String>,
-    state: RefCell<State>
+// Running totals of the samples handled by a data source.
+#[derive(Debug, Default)]
+pub struct Counters {
+    received: AtomicUsize,
+    merged: AtomicUsize,
+    discarded: AtomicUsize,
+    malformed: AtomicUsize,
+    rejected: AtomicUsize,
+    dropped: AtomicUsize
 }
 
-impl ReadSource {
-    pub fn new<R>(src: R) -> ReadSource where R: Read + Send + 'static {
-        let state = RefCell::new(State::new());
-        let (sender, receiver) = sync_channel(0);
+impl Counters {
+    // Number of samples read from the source.
+    pub fn received(&self) -> usize {
+        self.received.load(Ordering::Relaxed)
+    }
 
-        spawn(move || {
-            let mut reader = BufReader::new(src);
-            loop {
-                let mut line = String::new();
-                reader.read_line(&mut line);
-
-                match sender.try_send(line) {
-                    Ok(_) => (),
-                    Err(TrySendError::Full(_)) => println!("full"),
-                    Err(TrySendError::Disconnected(_)) => {
-                        panic!("noooo!");
+    // Number of samples merged into the state.
+    pub fn merged(&self) -> usize {
+        self.merged.load(Ordering::Relaxed)
+    }
+
+    // Number of samples which contained no usable values.
+    pub fn discarded(&self) -> usize {
+        self.discarded.load(Ordering::Relaxed)
+    }
+
+    // Number of samples which could not be parsed at all.
+    pub fn malformed(&self) -> usize {
+        self.malformed.load(Ordering::Relaxed)
+    }
+
+    // Number of individual values dropped from otherwise valid samples.
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    // Number of samples the source sent which never arrived.
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+
+// The latest state of a data source.
+//
+// Reader threads merge samples into the state as soon as they arrive,
+// while the render loop takes a snapshot of it once per frame. So the
+// render loop never waits on input, and input is never dropped because
+// the render loop is busy.
+#[derive(Clone)]
+pub struct Shared {
+    state: Arc<Mutex<State>>,
+    // Converts incoming samples to channel values. It's kept apart from
+    // the state, so that snapshots don't copy it. When both are locked,
+    // the pipeline is locked first.
+    pipeline: Arc<Mutex<Pipeline>>,
+    counters: Arc<Counters>,
+    clock: Clock,
+    // Receives a copy of every merged sample.
+    log: Option<Sender<Sample>>,
+    // Producer to which samples merged through this handle are
+    // attributed.
+    producer: Option<String>
+}
+
+impl Shared {
+    pub fn new(state: State) -> Shared {
+        Shared::with_pipeline(state, Pipeline::default())
+    }
+
+    // Create a shared state, which processes samples with the given
+    // pipeline.
+    pub fn with_pipeline(state: State, pipeline: Pipeline) -> Shared {
+        Shared {
+            state: Arc::new(Mutex::new(state)),
+            pipeline: Arc::new(Mutex::new(pipeline)),
+            counters: Arc::new(Counters::default()),
+            clock: Clock::new(),
+            log: None,
+            producer: None
+        }
+    }
+
+    // Create a shared state, which processes and logs samples as
+    // configured.
+    pub fn from_config(config: &Config) -> Shared {
+        let mut shared = Shared::with_pipeline(
+            State::from_config(config),
+            Pipeline::from_config(config)
+        );
+
+        if let Some(log) = &config.log {
+            let columns = config.channels
+                .iter()
+                .map(|c| c.input.as_ref().unwrap_or(&c.name).clone())
+                .fold(Vec::new(), |mut columns, input| {
+                    if !columns.contains(&input) {
+                        columns.push(input);
                     }
-                }
+                    columns
+                });
+
+            match Logger::new(log.clone(), columns) {
+                Ok(logger) => shared.log = Some(logger.spawn()),
+                Err(e) => eprintln!("Couldn't start log in {}: {}", log.directory, e)
             }
+        }
+
+        shared
+    }
+
+    // Current time on the local clock.
+    pub fn now(&self) -> Float {
+        self.clock.seconds()
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    // Return a handle to the same state, which attributes the samples
+    // merged through it to the named producer.
+    pub fn producer(&self, name: &str) -> Shared {
+        Shared {producer: Some(String::from(name)), ..self.clone()}
+    }
+
+    // Record that this handle's producer has disconnected, which makes
+    // the channels it last updated stale.
+    pub fn disconnect(&self) {
+        if let Some(producer) = &self.producer {
+            self.state.lock().unwrap().disconnect(producer);
+        }
+    }
+
+    // Merge a sample into the state, unless it is empty.
+    pub fn merge(&self, sample: Sample) {
+        self.merge_ref(&sample);
+    }
+
+    // Like `merge`, but leaves the sample to the caller, who may reuse
+    // it.
+    pub fn merge_ref(&self, sample: &Sample) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+
+        if sample.values.is_empty() {
+            self.counters.discarded.fetch_add(1, Ordering::Relaxed);
+        } else {
+            if let Some(log) = &self.log {
+                // The logger only stops if it panics, so ignore errors.
+                let _ = log.send(sample.clone());
+            }
+            let mut pipeline = self.pipeline.lock().unwrap();
+            let mut state = self.state.lock().unwrap();
+            let changed = state.apply(&mut pipeline, sample);
+            if let Some(producer) = &self.producer {
+                state.attribute(producer, changed);
+            }
+            drop(state);
+            pipeline.flush();
+            drop(pipeline);
+            self.counters.merged.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    // Count a sample which could not be parsed.
+    pub fn malformed(&self) {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        self.counters.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Count values dropped from a sample.
+    pub fn rejected(&self, count: usize) {
+        self.counters.rejected.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Count samples known to have been lost in transit.
+    pub fn dropped(&self, count: usize) {
+        self.counters.dropped.fetch_add(count, Ordering::Relaxed);
+    }
+
+    // Record that the source has ended, and save any persisted values.
+    pub fn end(&self) {
+        let mut pipeline = self.pipeline.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        let time = state.time;
+        state.source_lost = true;
+        drop(state);
+        pipeline.save(time);
+    }
+
+    // Record that a source which had ended has started again.
+    pub fn restart(&self) {
+        self.state.lock().unwrap().source_lost = false;
+    }
+
+    // Return a copy of the current state, with stale channels flagged.
+    pub fn snapshot(&self, channels: &[Channel]) -> State {
+        let mut state = self.state.lock().unwrap().clone();
+        state.check_stale(channels, self.now());
+        state
+    }
+}
+
+
+// A data source whose input is read on a background thread, and merged
+// into a shared state.
+pub struct ThreadedSource {
+    shared: Shared,
+    channels: Vec<Channel>
+}
+
+impl ThreadedSource {
+    // Read input on a new thread, with `read`. The source ends when
+    // `read` returns.
+    pub fn spawn<F>(config: &Config, read: F) -> ThreadedSource
+    where F: FnOnce(&Shared, &Input) + Send + 'static {
+        ThreadedSource::start(config, false, read)
+    }
+
+    // Like `spawn`, for a source which has no data until it connects.
+    pub fn connecting<F>(config: &Config, read: F) -> ThreadedSource
+    where F: FnOnce(&Shared, &Input) + Send + 'static {
+        ThreadedSource::start(config, true, read)
+    }
+
+    fn start<F>(config: &Config, ended: bool, read: F) -> ThreadedSource
+    where F: FnOnce(&Shared, &Input) + Send + 'static {
+        let shared = Shared::from_config(config);
+        let input = config.input.clone();
+        let sink = shared.clone();
+
+        if ended {
+            shared.end();
+        }
+
+        spawn(move || {
+            read(&sink, &input);
+            sink.end();
         });
 
-        ReadSource {receiver, state}
+        ThreadedSource {shared, channels: config.channels.clone()}
+    }
+
+    pub fn counters(&self) -> &Counters {
+        self.shared.counters()
     }
 }
 
-impl DataSource for ReadSource {
+impl DataSource for ThreadedSource {
     fn get_state(&self) -> State {
-        let line = self.receiver.recv().unwrap();
-        let sample = if let Ok(values) = serde_json::from_str(&line) {
-            Sample {values, time: 0.0}
-        } else {
-            Sample {values: HashMap::new(), time: 0.0}
-        };
+        self.shared.snapshot(&self.channels)
+    }
+}
+
+
+// Reads samples from a stream of JSON maps, one per line, or of binary
+// frames, as described in `binary.rs`, according to `input.format`.
+//
+// If `timestamp` is given, each sample is stamped with the value of
+// that key, which is removed from the sample. Otherwise, the sample is
+// stamped with the time it was received.
+//
+// Lines which aren't JSON maps, or lack the `timestamp` key, are
+// skipped, as are any values which aren't numbers. The reader stops at
+// the end of the stream, or on an I/O error, and flags the state as
+// `source_lost`.
+pub fn read_source<R>(
+    src: R,
+    config: &Config
+) -> ThreadedSource where R: Read + Send + 'static {
+    ThreadedSource::spawn(config, move |sink, input| {
+        read_input(src, sink, input)
+    })
+}
+
+// Merge a stream in the configured format into the shared state.
+pub(crate) fn read_input<R: Read>(mut src: R, sink: &Shared, input: &Input) {
+    let mut prefix = Vec::new();
+    let format = match input.format {
+        InputFormat::Auto => detect(&mut src, &mut prefix),
+        format => format
+    };
+
+    if input.log_errors && input.format == InputFormat::Auto {
+        eprintln!("Reading {:?} input.", format);
+    }
+
+    let src = Cursor::new(prefix).chain(src);
+    match format {
+        InputFormat::Json | InputFormat::Auto => read_lines(src, sink, input),
+        InputFormat::Binary => binary::read_frames(src, sink, input),
+        InputFormat::MessagePack =>
+            packed::read_maps(src, Encoding::MessagePack, sink, input),
+        InputFormat::Cbor => packed::read_maps(src, Encoding::Cbor, sink, input)
+    }
+}
+
+// Guess the format of a stream from its first byte, other than
+// whitespace, keeping the bytes read in `prefix`. Anything which isn't
+// recognized is taken to be JSON.
+//
+// MessagePack and CBOR maps are preceded by their length, whose first
+// byte is zero, so those are told apart by the first byte of the map.
+fn detect<R: Read>(src: &mut R, prefix: &mut Vec<u8>) -> InputFormat {
+    let mut byte = [0];
+    loop {
+        match src.read(&mut byte) {
+            Ok(0) | Err(_) => return InputFormat::Json,
+            Ok(_) => prefix.push(byte[0])
+        }
+
+        match byte[0] {
+            b' ' | b'\t' | b'\r' | b'\n' => (),
+            byte if byte == binary::MAGIC[0] => return InputFormat::Binary,
+            0 => {
+                let mut rest = [0; 4];
+                if src.read_exact(&mut rest).is_err() {
+                    return InputFormat::Json;
+                }
+                prefix.extend(&rest);
+
+                return match packed::map_encoding(rest[3]) {
+                    Some(Encoding::MessagePack) => InputFormat::MessagePack,
+                    Some(Encoding::Cbor) => InputFormat::Cbor,
+                    None => InputFormat::Json
+                };
+            },
+            _ => return InputFormat::Json
+        }
+    }
+}
+
+// Merge each line of a stream into the shared state, until the end of
+// the stream, or an I/O error.
+pub(crate) fn read_lines<R: Read>(src: R, sink: &Shared, input: &Input) {
+    let mut reader = BufReader::new(src);
+    loop {
+        let mut line = Vec::new();
+        match reader.read_until(b'\n', &mut line) {
+            Ok(0) => {
+                if input.log_errors {
+                    eprintln!("End of input.");
+                }
+                break;
+            },
+            Ok(_) => merge_line(&line, sink, input),
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading input: {}", e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+// Parse a line of input, and merge it into the shared state.
+pub(crate) fn merge_line(line: &[u8], sink: &Shared, input: &Input) {
+    let text = match str::from_utf8(line) {
+        Ok(text) => text.trim(),
+        Err(_) => {
+            if input.log_errors {
+                eprintln!("Invalid UTF-8: {:?}", String::from_utf8_lossy(line));
+            }
+            sink.malformed();
+            return;
+        }
+    };
+
+    if text.is_empty() {
+        return;
+    }
+
+    match parse(text, sink.now(), &input.timestamp) {
+        Ok((sample, rejected)) => {
+            if input.log_errors && !rejected.is_empty() {
+                eprintln!("Non-numeric values for {:?}: {}", rejected, text);
+            }
+            sink.rejected(rejected.len());
+            sink.merge(sample);
+        },
+        Err(error) => {
+            if input.log_errors {
+                eprintln!("{}: {}", error, text);
+            }
+            sink.malformed();
+        }
+    }
+}
+
+// Parse a line of input, received at the given time.
+//
+// Returns the sample, and the keys of any values which were not
+// numbers, or why the line isn't a sample.
+pub(crate) fn parse(
+    line: &str,
+    received: Float,
+    timestamp: &Option<Timestamp>
+) -> Result<(Sample, Vec<String>), &'static str> {
+    let map: HashMap<String, Value> = serde_json::from_str(line)
+        .map_err(|_| "Not a JSON map")?;
+    let mut values = HashMap::new();
+    let mut rejected = Vec::new();
+
+    for (key, value) in map {
+        match value.as_f64() {
+            Some(value) => {values.insert(key, value);},
+            None => rejected.push(key)
+        }
+    }
+
+    match stamp(values, received, timestamp) {
+        Some(sample) => Ok((sample, rejected)),
+        None => Err("No timestamp")
+    }
+}
+
+// Make a sample of the given values, taking its time from the
+// `timestamp` key, if one is configured, or else the time it was
+// received.
+//
+// Returns `None` if the key is configured but missing: the receive
+// clock has a different origin, so falling back to it would put the
+// sample out of order.
+pub(crate) fn stamp(
+    mut values: HashMap<String, Float>,
+    received: Float,
+    timestamp: &Option<Timestamp>
+) -> Option<Sample> {
+    let time = match timestamp {
+        Some(ts) => ts.to_seconds(values.remove(ts.key())?),
+        None => received
+    };
+
+    Some(Sample {values, time, received})
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(time: Float, values: &[(&str, Float)]) -> Sample {
+        Sample {
+            values: values.iter().map(|(k, v)| (String::from(*k), *v)).collect(),
+            time,
+            received: time
+        }
+    }
+
+    #[test]
+    fn test_summaries() {
+        let mut state = State::with_stats_reset(Some(String::from("RESET")));
+
+        state.update(sample(0.0, &[("ECT", 180.0), ("RESET", 0.0)]));
+        state.update(sample(1.0, &[("ECT", 210.0)]));
+        state.update(sample(2.0, &[("ECT", 195.0)]));
+
+        assert_eq!(state.get(&String::from("ECT.min")), Some(180.0));
+        assert_eq!(state.get(&String::from("ECT.max")), Some(210.0));
+        assert_eq!(state.get(&String::from("ECT.mean")), Some(195.0));
+        assert_eq!(state.summaries[&String::from("ECT")].count, 3);
+
+        state.update(sample(3.0, &[("ECT", 200.0), ("RESET", 1.0)]));
+        assert_eq!(state.get(&String::from("ECT.max")), None);
+
+        state.update(sample(4.0, &[("ECT", 190.0), ("RESET", 0.0)]));
+        assert_eq!(state.get(&String::from("ECT.max")), Some(190.0));
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let timestamp = Some(Timestamp::Milliseconds(String::from("t")));
+
+        let (sample, _) = parse("{\"t\": 1500, \"RPM\": 3000}", 7.0, &timestamp).unwrap();
+        assert_eq!(sample.time, 1.5);
+        assert_eq!(sample.values.get("t"), None);
 
-        self.state.borrow_mut().update(sample);
-        self.state.borrow().clone()
+        assert_eq!(parse("{\"RPM\": 3000}", 7.0, &timestamp).err(), Some("No timestamp"));
+        assert_eq!(parse("{\"RPM\": 3000}", 7.0, &None).unwrap().0.time, 7.0);
     }
 }
@@ -19,6 +19,7 @@
 use crate::render::CairoRenderer;
 use crate::data::{State, DataSource};
 use crate::clock::Clock;
+use crate::logic::Evaluator;
 
 use std::{
     cell::RefCell,
@@ -27,7 +28,9 @@ use std::{
     os::unix::io::{
         RawFd,
         AsRawFd
-    }
+    },
+    thread::sleep,
+    time::Duration
 };
 
 use cairo::{Context, Format, ImageSurface};
@@ -175,10 +178,11 @@ impl Page {
         &self,
         surface: &ImageSurface,
         state: &State,
-        renderer: &CairoRenderer
+        renderer: &CairoRenderer,
+        time: f64
     ) {
         let cr = Context::new(&surface);
-        renderer.render(&cr, &state);
+        renderer.render(&cr, &state, time);
     }
 
     pub fn render(
@@ -186,7 +190,8 @@ impl Page {
         card: &Card,
         renderer: &CairoRenderer,
         crtc: crtc::Handle,
-        state: &State
+        state: &State,
+        time: f64
     ) {
         // I tried so hard to optimize this code to re-use the
         // dumbbuffer, mapping, and cairo context. It worked fine on
@@ -203,7 +208,7 @@ impl Page {
         // XXX: if we can't avoid the memcpy anyway, is it possible /
         // better to *write* to the framebuffer?
         let mut dm = db.map(card).expect("couldn't map buffer");
-        self.render_priv(&s, state, renderer);
+        self.render_priv(&s, state, renderer, time);
 
         dm.as_mut().copy_from_slice(
             s.get_data().expect("couldn't borrow image data").as_mut()
@@ -220,27 +225,41 @@ impl Page {
 }
 
 
-// Loop forever rendering things al the things.
+// Loop forever rendering things al the things, at no more than the
+// given frame rate.
 fn render_loop<DS>(
     card: Card,
     crtc: crtc::Handle,
     renderer: CairoRenderer,
     pages: [Page; 2],
-    data: DS
+    data: DS,
+    logic: Evaluator,
+    frame_rate: f64
 ) where DS: DataSource {
-    let _clock = Clock::new();
+    let clock = Clock::new();
+    let period = 1.0 / frame_rate;
     for page in pages.iter().cycle() {
-        page.render(&card, &renderer, crtc, &data.get_state());
+        let start = clock.seconds();
+        let mut state = data.get_state();
+        logic.update(&mut state);
+        page.render(&card, &renderer, crtc, &state, start);
+
+        let elapsed = clock.seconds() - start;
+        if elapsed < period {
+            sleep(Duration::from_secs_f64(period - elapsed));
+        }
     }
 }
 
 
-// Run forever, redrawing the screen as fast as possible, using
+// Run forever, redrawing the screen at the given frame rate, using
 // double-buffering.
 pub fn run<DS> (
     device: String,
     renderer: CairoRenderer,
-    data: DS
+    data: DS,
+    logic: Evaluator,
+    frame_rate: f64
 ) where DS: DataSource {
     let card = Card::open(&device);
 
@@ -283,5 +302,5 @@ pub fn run<DS> (
     crtc::set(&card, crtc.handle(), pages[0].fb, &con_hdl, orig, Some(mode))
         .expect("Could not set CRTC");
 
-    render_loop(card, crtc.handle(), renderer, pages, data);
+    render_loop(card, crtc.handle(), renderer, pages, data, logic, frame_rate);
 }
@@ -0,0 +1,376 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// NMEA GPS data source
+//
+// Reads NMEA 0183 sentences from a GPS receiver's serial device, or
+// from a file, and gives the position and motion they report as
+// inputs:
+//
+// - `GPS_LATITUDE` and `GPS_LONGITUDE`, in degrees, negative to the
+//   south and west.
+// - `GPS_SPEED`, in km/h, and `GPS_HEADING`, in degrees from true north.
+// - `GPS_ALTITUDE`, in meters above mean sea level.
+// - `GPS_SATELLITES`, the number of satellites in use.
+// - `GPS_FIX_QUALITY`, zero without a fix.
+//
+// `RMC`, `GGA` and `VTG` sentences are read, from any talker, and
+// others are ignored. Sentences with a missing or wrong checksum are
+// rejected, and position and motion are only given while the receiver
+// has a fix.
+//
+// Once an `RMC` sentence has given the date, samples are stamped with
+// GPS time, as seconds since the Unix epoch. A file is played back at
+// the rate it was recorded, by GPS time, except that gaps of more than
+// `MAX_GAP`, where the receiver lost its fix or the log was resumed,
+// are cut short.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    os::unix::fs::FileTypeExt,
+    str,
+    thread::sleep,
+    time::Duration
+};
+
+use crate::config::{Config, Float, Input};
+use crate::data::{Sample, Shared, ThreadedSource};
+use crate::obd::open_serial;
+
+// Speed of most receivers' serial ports.
+const BAUD: u32 = 9600;
+
+const KNOTS_TO_KPH: Float = 1.852;
+
+// Longest wait, in seconds, between samples played back from a file.
+const MAX_GAP: Float = 1.0;
+
+// Whether a sentence's checksum, the two hex digits after the `*`,
+// matches the exclusive-or of the characters between `$` and `*`.
+fn checksum_ok(body: &str, checksum: &str) -> bool {
+    match u8::from_str_radix(checksum, 16) {
+        Ok(expected) => body.bytes().fold(0, |sum, b| sum ^ b) == expected,
+        Err(_) => false
+    }
+}
+
+// Parse a latitude or longitude, given as degrees and minutes, e.g.
+// `4807.038` for 48 degrees and 7.038 minutes, with its hemisphere.
+fn angle(value: &str, hemisphere: &str) -> Option<Float> {
+    let value: Float = value.parse().ok()?;
+    let degrees = (value / 100.0).trunc();
+    let angle = degrees + (value - degrees * 100.0) / 60.0;
+
+    match hemisphere {
+        "N" | "E" => Some(angle),
+        "S" | "W" => Some(-angle),
+        _ => None
+    }
+}
+
+// Seconds since midnight, from `hhmmss.ss`.
+fn time_of_day(value: &str) -> Option<Float> {
+    let digits = value.as_bytes().get(..6)?;
+    if !digits.iter().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: Float = value[0..2].parse().ok()?;
+    let minutes: Float = value[2..4].parse().ok()?;
+    let seconds: Float = value[4..].parse().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+// Days since the Unix epoch, from `ddmmyy`.
+fn date(value: &str) -> Option<i64> {
+    if value.len() != 6 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let day: i64 = value[0..2].parse().ok()?;
+    let month: i64 = value[2..4].parse().ok()?;
+    let year: i64 = value[4..6].parse().ok()?;
+    let year = if year < 80 {2000 + year} else {1900 + year};
+
+    // Days from civil, after Howard Hinnant.
+    let (year, month) = if month <= 2 {(year - 1, month + 9)} else {(year, month - 3)};
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some(era * 146_097 + day_of_era - 719_468)
+}
+
+// Values read from a sentence, and the GPS time, if known.
+#[derive(Debug, PartialEq)]
+pub struct Fix {
+    pub values: HashMap<String, Float>,
+    pub time: Option<Float>
+}
+
+// A sentence which is malformed, or whose checksum is wrong.
+#[derive(Debug, PartialEq)]
+pub struct InvalidSentence;
+
+// Parses sentences, remembering the date, which only `RMC` gives.
+#[derive(Default)]
+pub struct Parser {
+    date: Option<i64>,
+    // The latest GPS time, given to sentences without one.
+    time: Option<Float>
+}
+
+impl Parser {
+    // Parse a sentence, or return `None` if it's one we ignore.
+    pub fn parse(&mut self, line: &str) -> Result<Option<Fix>, InvalidSentence> {
+        let line = line.trim();
+        let (body, checksum) = line
+            .strip_prefix('$')
+            .and_then(|line| line.split_once('*'))
+            .ok_or(InvalidSentence)?;
+
+        if !checksum_ok(body, checksum) {
+            return Err(InvalidSentence);
+        }
+
+        let fields: Vec<&str> = body.split(',').collect();
+        let field = |i: usize| fields.get(i).copied().unwrap_or("");
+        let number = |i: usize| field(i).parse::<Float>().ok();
+        let kind = match field(0).get(2..) {
+            Some(kind) => kind,
+            None => return Err(InvalidSentence)
+        };
+
+        let mut values = HashMap::new();
+        let mut insert = |key: &str, value: Option<Float>| if let Some(value) = value {
+            values.insert(String::from(key), value);
+        };
+
+        let time = match kind {
+            "RMC" => {
+                self.date = date(field(9)).or(self.date);
+                if field(2) == "A" {
+                    insert("GPS_LATITUDE", angle(field(3), field(4)));
+                    insert("GPS_LONGITUDE", angle(field(5), field(6)));
+                    insert("GPS_SPEED", number(7).map(|knots| knots * KNOTS_TO_KPH));
+                    insert("GPS_HEADING", number(8));
+                }
+                time_of_day(field(1))
+            },
+            "GGA" => {
+                let quality = number(6);
+                insert("GPS_FIX_QUALITY", quality);
+                insert("GPS_SATELLITES", number(7));
+                if quality.unwrap_or(0.0) > 0.0 {
+                    insert("GPS_LATITUDE", angle(field(2), field(3)));
+                    insert("GPS_LONGITUDE", angle(field(4), field(5)));
+                    insert("GPS_ALTITUDE", number(9));
+                }
+                time_of_day(field(1))
+            },
+            "VTG" => {
+                // Mode `N` means the data isn't valid. Older receivers
+                // don't give a mode.
+                if field(9) != "N" {
+                    insert("GPS_HEADING", number(1));
+                    insert("GPS_SPEED", number(7));
+                }
+                None
+            },
+            _ => return Ok(None)
+        };
+
+        if let (Some(date), Some(time)) = (self.date, time) {
+            let mut time = date as Float * 86400.0 + time;
+            // Past midnight, before the next `RMC` gives the new date.
+            if time < self.time.unwrap_or(time) - 43200.0 {
+                time += 86400.0;
+            }
+            self.time = Some(time);
+        }
+
+        Ok(Some(Fix {values, time: self.time}))
+    }
+}
+
+// Merge each sentence of a stream into the shared state. If `pace` is
+// set, sentences are merged at the rate they were recorded.
+fn read_nmea<R: Read>(src: R, pace: bool, sink: &Shared, input: &Input) {
+    let mut parser = Parser::default();
+    // The first GPS time, and the local time it was read.
+    let mut start = None;
+
+    let mut reader = BufReader::new(src);
+    loop {
+        let mut bytes = Vec::new();
+        match reader.read_until(b'\n', &mut bytes) {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading GPS: {}", e);
+                }
+                break;
+            }
+        }
+
+        let line = match str::from_utf8(&bytes) {
+            Ok(line) => line,
+            Err(_) => {
+                if input.log_errors {
+                    eprintln!("Invalid UTF-8: {:?}", String::from_utf8_lossy(&bytes));
+                }
+                sink.malformed();
+                continue;
+            }
+        };
+
+        let fix = match parser.parse(line) {
+            Ok(Some(fix)) => fix,
+            Ok(None) => continue,
+            Err(InvalidSentence) => {
+                if input.log_errors && !line.trim().is_empty() {
+                    eprintln!("Invalid NMEA sentence: {}", line.trim());
+                }
+                sink.malformed();
+                continue;
+            }
+        };
+
+        let received = sink.now();
+        if let (true, Some(time)) = (pace, fix.time) {
+            let (first, then) = *start.get_or_insert((time, received));
+            let wait = (time - first) - (received - then);
+            if !(-MAX_GAP..=MAX_GAP).contains(&wait) {
+                // Play on from here, as though the gap hadn't happened.
+                sleep(Duration::from_millis((wait.clamp(0.0, MAX_GAP) * 1000.0) as u64));
+                start = Some((time, sink.now()));
+            } else if wait > 0.0 {
+                sleep(Duration::from_millis((wait * 1000.0) as u64));
+            }
+        }
+
+        let time = fix.time.unwrap_or(received);
+        sink.merge(Sample {values: fix.values, time, received: sink.now()});
+    }
+}
+
+// Read a receiver's serial device, or play back a file.
+pub fn source(path: &str, config: &Config) -> io::Result<ThreadedSource> {
+    let device = fs::metadata(path)?.file_type().is_char_device();
+    let src = if device {open_serial(path, BAUD)?} else {File::open(path)?};
+
+    Ok(ThreadedSource::spawn(config, move |sink, input| {
+        read_nmea(src, !device, sink, input)
+    }))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::State;
+
+    fn value(fix: &Fix, key: &str) -> Float {
+        fix.values[&String::from(key)]
+    }
+
+    #[test]
+    fn test_sentences() {
+        let mut parser = Parser::default();
+
+        // Without a date, GGA has no time.
+        let fix = parser
+            .parse("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47")
+            .unwrap()
+            .unwrap();
+        assert!((value(&fix, "GPS_LATITUDE") - 48.1173).abs() < 1e-9);
+        assert!((value(&fix, "GPS_LONGITUDE") - 11.516_666_666).abs() < 1e-6);
+        assert_eq!(value(&fix, "GPS_ALTITUDE"), 545.4);
+        assert_eq!(value(&fix, "GPS_SATELLITES"), 8.0);
+        assert_eq!(value(&fix, "GPS_FIX_QUALITY"), 1.0);
+        assert_eq!(fix.time, None);
+
+        let fix = parser
+            .parse("$GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W*78")
+            .unwrap()
+            .unwrap();
+        assert!(value(&fix, "GPS_LONGITUDE") < 0.0);
+        assert!((value(&fix, "GPS_SPEED") - 41.4848).abs() < 1e-9);
+        assert_eq!(value(&fix, "GPS_HEADING"), 84.4);
+        // 1994-03-23 12:35:19 UTC.
+        assert_eq!(fix.time, Some(764_426_119.0));
+
+        let fix = parser
+            .parse("$GPVTG,054.7,T,034.4,M,005.5,N,010.2,K*48")
+            .unwrap()
+            .unwrap();
+        assert_eq!(value(&fix, "GPS_SPEED"), 10.2);
+        assert_eq!(value(&fix, "GPS_HEADING"), 54.7);
+        assert_eq!(fix.time, Some(764_426_119.0));
+
+        // No fix: only the quality and satellite count.
+        let fix = parser.parse("$GNGGA,000001,,,,,0,00,,,M,,M,,*79").unwrap().unwrap();
+        assert_eq!(fix.values.len(), 2);
+        assert_eq!(value(&fix, "GPS_FIX_QUALITY"), 0.0);
+        assert_eq!(fix.time, Some(764_467_201.0));
+
+        assert_eq!(parser.parse("$GPGSV,1,1,00*79"), Ok(None));
+        assert_eq!(parser.parse("$GPRMC,123519,A,4807.038,N*00"), Err(InvalidSentence));
+        assert_eq!(parser.parse("$GPRMC,123519,A,4807.038,N"), Err(InvalidSentence));
+        assert_eq!(parser.parse("garbage"), Err(InvalidSentence));
+
+        assert_eq!(time_of_day("123519.50"), Some(45_319.5));
+        assert_eq!(time_of_day("1\u{e9}3456"), None);
+        assert_eq!(time_of_day("12:35"), None);
+    }
+
+    #[test]
+    fn test_read() {
+        let log = "$GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W*78\r\n\
+                   $GPGSV,1,1,00*79\r\n\
+                   $GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*00\r\n\
+                   $GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n";
+        let sink = Shared::new(State::new());
+        read_nmea(log.as_bytes(), false, &sink, &Config::empty().input);
+
+        let state = sink.snapshot(&[]);
+        assert_eq!(state.time, 764_426_119.0);
+        assert_eq!(state.get(&String::from("GPS_SATELLITES")), Some(8.0));
+        assert_eq!(sink.counters().merged(), 2);
+        assert_eq!(sink.counters().malformed(), 1);
+
+        // Noise on the line is skipped, and reading carries on.
+        let mut log = b"$GPGSV,1,1,00*79\r\n\xff\xfe\r\n".to_vec();
+        log.extend(b"$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47\r\n");
+        let sink = Shared::new(State::new());
+        read_nmea(&log[..], false, &sink, &Config::empty().input);
+        assert_eq!(sink.counters().merged(), 1);
+        assert_eq!(sink.counters().malformed(), 1);
+
+        // Eleven hours pass between these fixes, but not in playback.
+        let log = "$GPRMC,123519,A,4807.038,N,01131.000,W,022.4,084.4,230394,003.1,W*78\r\n\
+                   $GNGGA,000001,,,,,0,00,,,M,,M,,*79\r\n";
+        let sink = Shared::new(State::new());
+        read_nmea(log.as_bytes(), true, &sink, &Config::empty().input);
+        assert_eq!(sink.counters().merged(), 2);
+        assert!(sink.now() < 2.0 * MAX_GAP);
+    }
+}
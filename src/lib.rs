@@ -23,13 +23,30 @@ extern crate gtk;
 
 
 pub mod ast;
+pub mod binary;
+pub mod can;
+pub mod cli;
 pub mod clock;
 pub mod config;
 pub mod data;
 pub mod env;
+pub mod gps;
+pub mod logic;
+pub mod logger;
+pub mod net;
+pub mod obd;
+pub mod packed;
+pub mod pipeline;
+pub mod racecapture;
+pub mod replay;
+pub mod simulate;
+pub mod unix;
 pub mod drm;
 pub mod windowed;
 pub mod render;
 pub mod typechecker;
 pub mod v1;
 pub mod vm;
+
+#[cfg(test)]
+mod testing;
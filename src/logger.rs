@@ -0,0 +1,309 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Session logging
+//
+// Every sample merged into the state is sent to a logger thread, which
+// writes it to disk, so that disk I/O never holds up input or
+// rendering.
+//
+// Samples are logged as they arrived, before calibration, so a log can
+// be replayed through a different config. Each session writes a new
+// set of files, named for the time the session started, and a file is
+// continued in a new one once it reaches `max_size` bytes. Existing
+// files are never overwritten: if a session started in the same second
+// as another, its name is given a suffix.
+//
+// `Lines` files hold one JSON map per line, in the same form as the
+// input, with the sample time under `time`. `Csv` files have a column
+// for the time, and one for each input read by a channel.
+//
+// Files are synced to disk every `sync_interval` seconds, so a power
+// cut loses at most that much data.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::spawn,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+
+use serde_json;
+
+use crate::config::{Float, Log, LogFormat};
+use crate::data::Sample;
+
+// An open log file.
+struct LogFile {
+    format: LogFormat,
+    writer: BufWriter<File>,
+    // Bytes written to this file so far.
+    size: u64,
+    // Position of this file in the session.
+    part: usize
+}
+
+pub struct Logger {
+    log: Log,
+    // Inputs which have a column in CSV files.
+    columns: Vec<String>,
+    // Name shared by all files from this session.
+    session: String,
+    files: Vec<LogFile>
+}
+
+impl Logger {
+    pub fn new(log: Log, columns: Vec<String>) -> io::Result<Logger> {
+        fs::create_dir_all(&log.directory)?;
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let name = format!("session-{}", started);
+
+        let mut logger = Logger {log, columns, session: name.clone(), files: Vec::new()};
+        let mut suffix = 1;
+        while let Err(e) = logger.open_all() {
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+
+            suffix += 1;
+            logger.session = format!("{}_{}", name, suffix);
+        }
+
+        Ok(logger)
+    }
+
+    // Open the first file of the session in each format. If any of them
+    // can't be opened, those which were are removed again.
+    fn open_all(&mut self) -> io::Result<()> {
+        for format in self.log.formats.clone() {
+            match self.open(format, 0) {
+                Ok(file) => self.files.push(file),
+                Err(e) => {
+                    for file in std::mem::take(&mut self.files) {
+                        let _ = fs::remove_file(self.path(file.format, 0));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Start a thread which logs every sample sent to it.
+    pub fn spawn(self) -> Sender<Sample> {
+        let (sender, receiver) = channel::<Sample>();
+        let interval = Duration::from_millis(
+            (self.log.sync_interval.unwrap_or(1.0) * 1000.0) as u64
+        );
+
+        spawn(move || {
+            let mut logger = self;
+            let mut synced = Instant::now();
+
+            loop {
+                match receiver.recv_timeout(interval) {
+                    Ok(sample) => logger.write(&sample),
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+
+                if synced.elapsed() >= interval {
+                    logger.sync();
+                    synced = Instant::now();
+                }
+            }
+
+            logger.sync();
+        });
+
+        sender
+    }
+
+    fn path(&self, format: LogFormat, part: usize) -> PathBuf {
+        let extension = match format {
+            LogFormat::Lines => "jsonl",
+            LogFormat::Csv => "csv"
+        };
+
+        let name = if part == 0 {
+            format!("{}.{}", self.session, extension)
+        } else {
+            format!("{}-{}.{}", self.session, part, extension)
+        };
+
+        Path::new(&self.log.directory).join(name)
+    }
+
+    fn open(&self, format: LogFormat, part: usize) -> io::Result<LogFile> {
+        let path = self.path(format, part);
+        let mut file = LogFile {
+            format,
+            writer: BufWriter::new(OpenOptions::new().write(true).create_new(true).open(path)?),
+            size: 0,
+            part
+        };
+
+        if format == LogFormat::Csv {
+            let header = csv_header(&self.columns);
+            file.writer.write_all(header.as_bytes())?;
+            file.size += header.len() as u64;
+        }
+
+        Ok(file)
+    }
+
+    fn write(&mut self, sample: &Sample) {
+        for i in 0..self.files.len() {
+            if let Err(e) = self.write_to(i, sample) {
+                eprintln!("Error writing log: {}", e);
+            }
+        }
+    }
+
+    fn write_to(&mut self, i: usize, sample: &Sample) -> io::Result<()> {
+        let file = &self.files[i];
+        if let Some(max_size) = self.log.max_size {
+            if file.size >= max_size {
+                let (format, part) = (file.format, file.part + 1);
+                self.files[i].writer.flush()?;
+                self.files[i] = self.open(format, part)?;
+            }
+        }
+
+        let file = &mut self.files[i];
+        let line = match file.format {
+            LogFormat::Lines => json_line(sample),
+            LogFormat::Csv => csv_line(sample, &self.columns)
+        };
+
+        file.writer.write_all(line.as_bytes())?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Flush every file, and wait for it to reach the disk.
+    fn sync(&mut self) {
+        for file in &mut self.files {
+            let result = file.writer
+                .flush()
+                .and_then(|_| file.writer.get_ref().sync_data());
+
+            if let Err(e) = result {
+                eprintln!("Error syncing log: {}", e);
+            }
+        }
+    }
+}
+
+// Format a number as JSON, which has no representation for NaN or
+// infinity.
+fn json_number(value: Float) -> String {
+    if value.is_finite() {
+        format!("{}", value)
+    } else {
+        String::from("null")
+    }
+}
+
+fn json_line(sample: &Sample) -> String {
+    let values: BTreeMap<&String, &Float> = sample.values.iter().collect();
+    let mut line = format!("{{\"time\":{}", json_number(sample.time));
+
+    for (key, value) in values {
+        let key = serde_json::to_string(key).unwrap_or_default();
+        line.push_str(&format!(",{}:{}", key, json_number(*value)));
+    }
+
+    line.push_str("}\n");
+    line
+}
+
+// Quote a CSV field, if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+fn csv_header(columns: &[String]) -> String {
+    let mut fields = vec! {String::from("time")};
+    fields.extend(columns.iter().map(|c| csv_field(c)));
+    fields.join(",") + "\n"
+}
+
+// Inputs which aren't in the sample are left blank.
+fn csv_line(sample: &Sample, columns: &[String]) -> String {
+    let mut fields = vec! {format!("{}", sample.time)};
+    fields.extend(columns.iter().map(|c| match sample.values.get(c) {
+        Some(value) => format!("{}", value),
+        None => String::new()
+    }));
+    fields.join(",") + "\n"
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sessions() {
+        let directory = std::env::temp_dir().join(format!("udashboard-log-{}", std::process::id()));
+        let log = Log {
+            directory: directory.to_string_lossy().into_owned(),
+            formats: vec! {LogFormat::Lines, LogFormat::Csv},
+            sync_interval: None,
+            max_size: None
+        };
+
+        // Sessions started within the same second get different names.
+        let first = Logger::new(log.clone(), Vec::new()).unwrap();
+        let second = Logger::new(log, Vec::new()).unwrap();
+        assert_ne!(first.session, second.session);
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 4);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_formats() {
+        let columns = vec! {String::from("RPM"), String::from("ECT")};
+        let sample = Sample {
+            values: vec! {(String::from("RPM"), 3000.0), (String::from("AIN0"), 2.25)}
+                .into_iter()
+                .collect(),
+            time: 12.5,
+            received: 12.5
+        };
+
+        assert_eq!(json_line(&sample), "{\"time\":12.5,\"AIN0\":2.25,\"RPM\":3000}\n");
+        assert_eq!(csv_header(&columns), "time,RPM,ECT\n");
+        assert_eq!(csv_line(&sample, &columns), "12.5,3000,\n");
+        assert_eq!(csv_field("A,B"), "\"A,B\"");
+    }
+}
@@ -0,0 +1,373 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Condition evaluation
+//
+// Each frame, every `When` in the config is tested against the
+// current data, and the result is recorded in `State::states`, keyed
+// by alarm name. An alarm is active if any of its rules hold.
+//
+// Conditions may depend on other alarms by name, so alarms are
+// resolved on demand, and memoized in `State::states` for the rest of
+// the frame.
+//
+// Some conditions depend on their own history, so conditions are
+// compiled into a tree of `Node`s, each stateful node owning a slot
+// in the evaluator's memory. Every node is evaluated exactly once per
+// frame, in order that timers and latches stay up to date.
+//
+// When several alarms are active at once, the one declared first in
+// the config takes precedence. Put the most urgent alarms first.
+
+use std::cell::Cell;
+
+use crate::config;
+use crate::config::{Condition, Float, Function, Logic, Test, When};
+use crate::data::State;
+
+// Runtime representation of a condition.
+enum Node {
+    Const(bool),
+    Is(String, Test),
+    Within(String, String, Function, Function),
+    Cond(String),
+    Not(Box<Node>),
+    And(Vec<Node>),
+    Or(Vec<Node>),
+    Xor(Vec<Node>),
+    Hysteresis(String, Test, Float, usize),
+    Debounce(Box<Node>, Float, Float, usize)
+}
+
+// History of a stateful node.
+#[derive(Copy, Clone, Debug)]
+struct Memory {
+    // The output of the node as of the previous frame.
+    active: bool,
+    // When the input started to disagree with the output, if it does.
+    changed: Option<Float>
+}
+
+impl Node {
+    fn compile(cond: &Condition, slots: &mut usize) -> Node {
+        match cond {
+            Condition::Always => Node::Const(true),
+            Condition::Never => Node::Const(false),
+            Condition::Is(channel, test) => Node::Is(channel.clone(), *test),
+            Condition::Within(channel, reference, lower, upper) => Node::Within(
+                channel.clone(),
+                reference.clone(),
+                lower.clone(),
+                upper.clone()
+            ),
+            Condition::Cond(name) => Node::Cond(name.clone()),
+            Condition::Not(c) => Node::Not(Box::new(Node::compile(c, slots))),
+            Condition::And(cs) => Node::And(Node::compile_all(cs, slots)),
+            Condition::Or(cs) => Node::Or(Node::compile_all(cs, slots)),
+            Condition::Xor(cs) => Node::Xor(Node::compile_all(cs, slots)),
+            Condition::Hysteresis(channel, test, band) => {
+                *slots += 1;
+                Node::Hysteresis(channel.clone(), *test, *band, *slots - 1)
+            },
+            Condition::Debounce(c, on, off) => {
+                let c = Box::new(Node::compile(c, slots));
+                *slots += 1;
+                Node::Debounce(c, *on, *off, *slots - 1)
+            }
+        }
+    }
+
+    fn compile_all(conds: &[Condition], slots: &mut usize) -> Vec<Node> {
+        conds.iter().map(|c| Node::compile(c, slots)).collect()
+    }
+}
+
+pub struct Evaluator {
+    rules: Vec<(String, Node)>,
+    alarms: Vec<String>,
+    memory: Vec<Cell<Memory>>
+}
+
+impl Evaluator {
+    pub fn new(logic: Logic) -> Evaluator {
+        let mut alarms: Vec<String> = Vec::new();
+        let mut rules = Vec::new();
+        let mut slots = 0;
+
+        for When(cond, state) in &logic {
+            if let config::State::Alarm(name) = state {
+                if !alarms.contains(name) {
+                    alarms.push(name.clone());
+                }
+                rules.push((name.clone(), Node::compile(cond, &mut slots)));
+            }
+        }
+
+        let memory = (0..slots)
+            .map(|_| Cell::new(Memory {active: false, changed: None}))
+            .collect();
+
+        Evaluator {rules, alarms, memory}
+    }
+
+    // Return the names of all alarms, in order of precedence.
+    pub fn alarms(&self) -> Vec<String> {
+        self.alarms.clone()
+    }
+
+    // Evaluate every rule against the given state, and record the
+    // result in `state.states`.
+    pub fn update(&self, state: &mut State) {
+        state.states.clear();
+
+        for name in &self.alarms {
+            self.resolve(name, state);
+        }
+    }
+
+    // Return whether the named alarm is active, evaluating its rules
+    // if this hasn't happened yet.
+    fn resolve(&self, name: &String, state: &mut State) -> bool {
+        if let Some(active) = state.states.get(name) {
+            return *active;
+        }
+
+        // The loader rejects cyclic conditions, but in case one slips
+        // through, treat the alarm as inactive while we evaluate it.
+        state.states.insert(name.clone(), false);
+
+        let mut active = false;
+        for (n, node) in &self.rules {
+            if n == name && self.eval(node, state) {
+                active = true;
+            }
+        }
+
+        state.states.insert(name.clone(), active);
+        active
+    }
+
+    fn eval(&self, node: &Node, state: &mut State) -> bool {
+        match node {
+            Node::Const(value) => *value,
+            Node::Is(channel, test) => test.check(state.get(channel)),
+            Node::Within(channel, reference, lower, upper) => {
+                match (state.get(channel), state.get(reference)) {
+                    (Some(value), Some(x)) =>
+                        lower.apply(x) <= value && value <= upper.apply(x),
+                    _ => false
+                }
+            },
+            Node::Cond(name) => self.resolve(name, state),
+            Node::Not(n) => !self.eval(n, state),
+            Node::And(ns) => self.each(ns, state).iter().all(|x| *x),
+            Node::Or(ns) => self.each(ns, state).iter().any(|x| *x),
+            Node::Xor(ns) => self
+                .each(ns, state)
+                .iter()
+                .filter(|x| **x)
+                .count() % 2 == 1,
+            Node::Hysteresis(channel, test, band, slot) => {
+                let mut memory = self.memory[*slot].get();
+                let test = if memory.active {test.widen(*band)} else {*test};
+                memory.active = test.check(state.get(channel));
+                self.memory[*slot].set(memory);
+                memory.active
+            },
+            Node::Debounce(n, on, off, slot) => {
+                let input = self.eval(n, state);
+                let mut memory = self.memory[*slot].get();
+
+                if input == memory.active {
+                    memory.changed = None;
+                } else {
+                    let since = memory.changed.unwrap_or(state.time);
+                    let delay = if input {*on} else {*off};
+                    if state.time - since >= delay {
+                        memory.active = input;
+                        memory.changed = None;
+                    } else {
+                        memory.changed = Some(since);
+                    }
+                }
+
+                self.memory[*slot].set(memory);
+                memory.active
+            }
+        }
+    }
+
+    // Evaluate every operand, without short-circuiting.
+    fn each(&self, nodes: &[Node], state: &mut State) -> Vec<bool> {
+        nodes.iter().map(|n| self.eval(n, state)).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Function, Test};
+    use Condition::*;
+
+    // Shortcut for a rule which sets the named alarm.
+    fn when(cond: Condition, name: &str) -> When {
+        When(cond, config::State::Alarm(String::from(name)))
+    }
+
+    // Shortcut for a condition on a single channel.
+    fn is(channel: &str, test: Test) -> Condition {
+        Is(String::from(channel), test)
+    }
+
+    // Evaluate the rules against the given channel values.
+    fn eval(logic: Logic, values: &[(&str, f64)]) -> State {
+        let mut state = State::new();
+        for (k, v) in values {
+            state.values.insert(String::from(*k), *v);
+        }
+        Evaluator::new(logic).update(&mut state);
+        state
+    }
+
+    fn active(state: &State, name: &str) -> bool {
+        state.states[&String::from(name)]
+    }
+
+    #[test]
+    fn test_simple() {
+        let logic = vec! {
+            when(is("ECT", Test::GreaterThan(215.0)), "ECT_HIGH"),
+            when(is("ECT", Test::LessThan(150.0)), "ECT_LOW"),
+            when(is("OILP", Test::LessThan(20.0)), "OILP_LOW")
+        };
+
+        let state = eval(logic, &[("ECT", 220.0)]);
+        assert!(active(&state, "ECT_HIGH"));
+        assert!(!active(&state, "ECT_LOW"));
+        // Missing channels never trigger an alarm.
+        assert!(!active(&state, "OILP_LOW"));
+    }
+
+    #[test]
+    fn test_compound() {
+        let logic = vec! {
+            when(is("RPM", Test::GreaterThan(1000.0)), "RUNNING"),
+            when(And(vec! {
+                Cond(String::from("RUNNING")),
+                Not(Box::new(Within(
+                    String::from("OILP"),
+                    String::from("RPM"),
+                    Function::Linear(0.005, 10.0),
+                    Function::Identity
+                )))
+            }), "OILP_LOW"),
+            when(Xor(vec! {Always, Always, Always}), "ODD"),
+            when(Xor(vec! {Always, Never, Always}), "EVEN")
+        };
+
+        let state = eval(logic.clone(), &[("RPM", 6000.0), ("OILP", 30.0)]);
+        assert!(active(&state, "RUNNING"));
+        assert!(active(&state, "OILP_LOW"));
+        assert!(active(&state, "ODD"));
+        assert!(!active(&state, "EVEN"));
+
+        let state = eval(logic.clone(), &[("RPM", 6000.0), ("OILP", 45.0)]);
+        assert!(!active(&state, "OILP_LOW"));
+
+        let state = eval(logic, &[("RPM", 800.0), ("OILP", 5.0)]);
+        assert!(!active(&state, "RUNNING"));
+        assert!(!active(&state, "OILP_LOW"));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let evaluator = Evaluator::new(vec! {
+            when(Hysteresis(
+                String::from("ECT"),
+                Test::GreaterThan(215.0),
+                5.0
+            ), "ECT_HIGH")
+        });
+
+        let mut state = State::new();
+        for (value, expected) in &[
+            (214.0, false),
+            (216.0, true),
+            (214.0, true),
+            (211.0, true),
+            (209.0, false),
+            (214.0, false)
+        ] {
+            state.values.insert(String::from("ECT"), *value);
+            evaluator.update(&mut state);
+            assert_eq!(active(&state, "ECT_HIGH"), *expected);
+        }
+    }
+
+    #[test]
+    fn test_debounce() {
+        let evaluator = Evaluator::new(vec! {
+            when(Debounce(
+                Box::new(is("OILP", Test::LessThan(20.0))),
+                1.0,
+                2.0
+            ), "OILP_LOW")
+        });
+
+        let mut state = State::new();
+        for (time, value, expected) in &[
+            // A momentary dip is ignored.
+            (0.0, 10.0, false),
+            (0.5, 10.0, false),
+            (0.7, 40.0, false),
+            // A sustained dip is not.
+            (1.0, 10.0, false),
+            (1.9, 10.0, false),
+            (2.0, 10.0, true),
+            // And the alarm holds until pressure has recovered for a
+            // while.
+            (2.5, 40.0, true),
+            (3.0, 10.0, true),
+            (3.5, 40.0, true),
+            (5.4, 40.0, true),
+            (5.5, 40.0, false)
+        ] {
+            state.time = *time;
+            state.values.insert(String::from("OILP"), *value);
+            evaluator.update(&mut state);
+            assert_eq!(active(&state, "OILP_LOW"), *expected);
+        }
+    }
+
+    #[test]
+    fn test_precedence() {
+        let logic = vec! {
+            when(is("ECT", Test::GreaterThan(230.0)), "ECT_CRITICAL"),
+            when(is("ECT", Test::GreaterThan(215.0)), "ECT_HIGH"),
+            when(is("OILP", Test::LessThan(20.0)), "ECT_CRITICAL")
+        };
+
+        let names: Vec<String> = vec! {"ECT_CRITICAL", "ECT_HIGH"}
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        assert_eq!(Evaluator::new(logic).alarms(), names);
+    }
+}
@@ -17,44 +17,58 @@
 // <https://www.gnu.org/licenses/>.
 
 use std::{
-    collections::HashMap,
     env::args,
-    io::stdin
+    process::exit
 };
 
 use udashboard::v1;
 use udashboard::{
+    cli,
     config::{Style, Pattern, Color},
     drm,
     render::{CairoRenderer, PNGRenderer},
-    data::{State, ReadSource},
+    data::State,
+    logic::Evaluator,
     vm
 };
 
 fn main() {
-    let config = v1::load(args().nth(1).unwrap())
+    let options = cli::parse(args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}\n\nUsage: udashboard <config> [device] [options]", e);
+        eprintln!("{}", cli::USAGE);
+        exit(1);
+    });
+
+    let mut config = v1::load(options.config.clone())
         .expect("couldn't load config");
+    if let Some(format) = options.format {
+        config.input.format = format;
+    }
+
+    let logic = Evaluator::new(config.logic.clone());
 
     let renderer = CairoRenderer::new(
         config.screen,
-        config.pages,
+        config.pages.clone(),
+        logic.alarms(),
         Style {
             background: Pattern::Solid(Color(0.0, 0.0, 0.0, 1.0)),
             foreground: Pattern::Solid(Color(1.0, 1.0, 1.0, 1.0)),
             indicator: Pattern::Solid(Color(1.0, 0.0, 0.0, 1.0)),
-        }
+        },
+        config.slow_blink,
+        config.fast_blink
     );
 
-    if let Some(path) = args().nth(2) {
-        drm::run(path, renderer, ReadSource::new(stdin()));
+    if let Some(path) = options.args.get(0) {
+        let data = options.source
+            .open(&config)
+            .expect("couldn't open data source");
+        drm::run(path.clone(), renderer, data, logic, config.frame_rate);
     } else {
         println!("No device path given, rendering to png.");
 
-        let mut state = State {
-            values: HashMap::new(),
-            states: HashMap::new(),
-            time: 0.0
-        };
+        let mut state = State::new();
 
         state.values.insert("RPM".to_string(), 1500.0);
         state.values.insert("OIL_PRESSURE".to_string(), 45.0);
@@ -62,11 +76,12 @@ fn main() {
         state.values.insert("SESSION_TIME".to_string(), 105.0);
         state.values.insert("GEAR".to_string(), 5.0);
         state.values.insert("RPM".to_string(), 1500.0);
+        logic.update(&mut state);
 
         PNGRenderer::new(
             "screenshot.png".to_string(),
             config.screen,
             renderer
-        ).render(&state);
+        ).render(&state, 0.0);
     }
 }
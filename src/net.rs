@@ -0,0 +1,279 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Network data sources
+//
+// These read the same line-delimited JSON as `read_source`, over UDP or
+// TCP. A UDP datagram may hold any number of lines.
+//
+// Each source may be restricted to a list of peer addresses, in which
+// case anything from any other address is ignored. An empty list
+// allows every address.
+//
+// A TCP source flags the state as `source_lost` while it has no
+// connection. A source which connects retries every second until it
+// succeeds, and again whenever the connection drops. A source which
+// listens accepts one connection at a time. A connection which has
+// been silent for `IDLE` is taken to have dropped, since a peer which
+// lost power, or a link which went down, never closes it.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    thread::sleep,
+    time::Duration
+};
+
+use crate::config::{Config, Input};
+use crate::data::{merge_line, read_input, DataSource, Shared, State, ThreadedSource};
+
+// Delay between attempts to connect.
+const RETRY: Duration = Duration::from_secs(1);
+
+// Longest a TCP connection may go without data before it's dropped.
+const IDLE: Duration = Duration::from_secs(10);
+
+// Largest datagram we accept.
+const MAX_DATAGRAM: usize = 65536;
+
+fn allowed(allow: &[IpAddr], addr: &SocketAddr) -> bool {
+    allow.is_empty() || allow.contains(&addr.ip())
+}
+
+// Reads datagrams sent to a UDP port.
+pub struct UdpSource {
+    source: ThreadedSource,
+    local: SocketAddr
+}
+
+impl UdpSource {
+    pub fn new(
+        bind: &str,
+        allow: Vec<IpAddr>,
+        config: &Config
+    ) -> io::Result<UdpSource> {
+        let socket = UdpSocket::bind(bind)?;
+        let local = socket.local_addr()?;
+
+        let source = ThreadedSource::spawn(config, move |sink, input| {
+            receive(socket, &allow, sink, input)
+        });
+
+        Ok(UdpSource {source, local})
+    }
+
+    // The address the source is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local
+    }
+}
+
+impl DataSource for UdpSource {
+    fn get_state(&self) -> State {
+        self.source.get_state()
+    }
+}
+
+fn receive(socket: UdpSocket, allow: &[IpAddr], sink: &Shared, input: &Input) {
+    let mut buffer = vec! {0; MAX_DATAGRAM};
+
+    loop {
+        match socket.recv_from(&mut buffer) {
+            Ok((_, peer)) if !allowed(allow, &peer) => {
+                if input.log_errors {
+                    eprintln!("Ignoring datagram from {}", peer);
+                }
+            },
+            Ok((len, _)) => {
+                for line in buffer[..len].split(|b| *b == b'\n') {
+                    merge_line(line, sink, input);
+                }
+            },
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error receiving datagram: {}", e);
+                }
+                sleep(RETRY);
+            }
+        }
+    }
+}
+
+
+pub enum Tcp {
+    // Connect to the given address.
+    Connect(String),
+    // Accept connections on the given address.
+    Listen(String)
+}
+
+// Reads a TCP stream.
+pub struct TcpSource {
+    source: ThreadedSource,
+    local: Option<SocketAddr>
+}
+
+impl TcpSource {
+    pub fn new(
+        tcp: Tcp,
+        allow: Vec<IpAddr>,
+        config: &Config
+    ) -> io::Result<TcpSource> {
+        match tcp {
+            Tcp::Connect(addr) => {
+                let source = ThreadedSource::connecting(config, move |sink, input| {
+                    connect(&addr, &allow, sink, input)
+                });
+                Ok(TcpSource {source, local: None})
+            },
+            Tcp::Listen(addr) => {
+                let listener = TcpListener::bind(addr)?;
+                let local = Some(listener.local_addr()?);
+                let source = ThreadedSource::connecting(config, move |sink, input| {
+                    accept(listener, &allow, sink, input)
+                });
+                Ok(TcpSource {source, local})
+            }
+        }
+    }
+
+    // The address the source is listening on, if it listens.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local
+    }
+}
+
+impl DataSource for TcpSource {
+    fn get_state(&self) -> State {
+        self.source.get_state()
+    }
+}
+
+// Read a connection until it closes, or goes silent.
+fn read_stream(stream: TcpStream, sink: &Shared, input: &Input) {
+    if let Err(e) = stream.set_read_timeout(Some(IDLE)) {
+        if input.log_errors {
+            eprintln!("Error setting read timeout: {}", e);
+        }
+        return;
+    }
+
+    sink.restart();
+    read_input(stream, sink, input);
+    sink.end();
+}
+
+fn connect(addr: &str, allow: &[IpAddr], sink: &Shared, input: &Input) {
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => match stream.peer_addr() {
+                Ok(peer) if allowed(allow, &peer) =>
+                    read_stream(stream, sink, input),
+                Ok(peer) => if input.log_errors {
+                    eprintln!("Refusing to read from {}", peer);
+                },
+                Err(e) => if input.log_errors {
+                    eprintln!("Error connecting to {}: {}", addr, e);
+                }
+            },
+            Err(e) => if input.log_errors {
+                eprintln!("Error connecting to {}: {}", addr, e);
+            }
+        }
+
+        sleep(RETRY);
+    }
+}
+
+fn accept(listener: TcpListener, allow: &[IpAddr], sink: &Shared, input: &Input) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => match stream.peer_addr() {
+                Ok(peer) if allowed(allow, &peer) =>
+                    read_stream(stream, sink, input),
+                Ok(peer) => if input.log_errors {
+                    eprintln!("Refusing connection from {}", peer);
+                },
+                Err(e) => if input.log_errors {
+                    eprintln!("Error accepting connection: {}", e);
+                }
+            },
+            Err(e) => if input.log_errors {
+                eprintln!("Error accepting connection: {}", e);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::testing::wait_for;
+
+    fn rpm(state: &State) -> Option<f64> {
+        state.get(&String::from("RPM"))
+    }
+
+    #[test]
+    fn test_allowed() {
+        let addr: SocketAddr = "10.0.0.2:9000".parse().unwrap();
+        assert!(allowed(&[], &addr));
+        assert!(allowed(&["10.0.0.2".parse().unwrap()], &addr));
+        assert!(!allowed(&["10.0.0.3".parse().unwrap()], &addr));
+    }
+
+    #[test]
+    fn test_udp() {
+        let source = UdpSource::new("127.0.0.1:0", Vec::new(), &Config::empty())
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        socket
+            .send_to(b"{\"RPM\": 1000}\n{\"RPM\": 2000}\n", source.local_addr())
+            .unwrap();
+
+        let state = wait_for(&source, |s| rpm(s) == Some(2000.0));
+        assert_eq!(rpm(&state), Some(2000.0));
+    }
+
+    #[test]
+    fn test_tcp() {
+        let source = TcpSource::new(
+            Tcp::Listen(String::from("127.0.0.1:0")),
+            Vec::new(),
+            &Config::empty()
+        ).unwrap();
+        assert!(source.get_state().source_lost);
+
+        let addr = source.local_addr().unwrap();
+        for value in &[1000, 2000] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            writeln!(stream, "{{\"RPM\": {}}}", value).unwrap();
+
+            let state = wait_for(&source, |s| rpm(s) == Some(*value as f64));
+            assert_eq!(rpm(&state), Some(*value as f64));
+            assert!(!state.source_lost);
+
+            // Closing the connection loses the source, until the next.
+            drop(stream);
+            assert!(wait_for(&source, |s| s.source_lost).source_lost);
+        }
+    }
+}
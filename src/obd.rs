@@ -0,0 +1,519 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// OBD-II data source
+//
+// Polls an ELM327 adapter, over a serial device, for the parameters
+// listed in the config, one after another, at a fixed rate. Each
+// parameter's value is given under an input named for it, such as
+// `OBD_RPM`, in the units of the OBD-II standard.
+//
+// A parameter the vehicle doesn't support answers `NO DATA`, and is
+// simply skipped. If the adapter stops answering altogether, the
+// source is flagged as lost, and the adapter is reset until it
+// answers again. The source ends if the device goes away.
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::io::AsRawFd,
+    sync::mpsc::{channel, Receiver, RecvTimeoutError},
+    thread::{sleep, spawn},
+    time::{Duration, Instant}
+};
+
+use nix::sys::termios::{cfmakeraw, cfsetspeed, tcgetattr, tcsetattr, BaudRate, SetArg};
+
+use crate::config::{Config, Float, Input, Obd, Pid};
+use crate::data::{Sample, Shared, ThreadedSource};
+
+// Commands which reset the adapter, and set it up for polling: echo
+// and linefeeds off, headers off, and automatic protocol selection.
+const INIT: [&str; 5] = ["ATZ", "ATE0", "ATL0", "ATH0", "ATSP0"];
+
+// Delay between attempts to reset an adapter which isn't answering.
+const RETRY: Duration = Duration::from_secs(1);
+
+// How long the adapter must be silent for any late replies to have
+// arrived.
+const QUIET: Duration = Duration::from_millis(250);
+
+// The mode 01 code for a parameter.
+fn code(pid: Pid) -> u8 {
+    match pid {
+        Pid::EngineLoad => 0x04,
+        Pid::CoolantTemp => 0x05,
+        Pid::ShortFuelTrim => 0x06,
+        Pid::LongFuelTrim => 0x07,
+        Pid::FuelPressure => 0x0a,
+        Pid::IntakePressure => 0x0b,
+        Pid::Rpm => 0x0c,
+        Pid::Speed => 0x0d,
+        Pid::TimingAdvance => 0x0e,
+        Pid::IntakeTemp => 0x0f,
+        Pid::MafRate => 0x10,
+        Pid::ThrottlePosition => 0x11,
+        Pid::RunTime => 0x1f,
+        Pid::FuelLevel => 0x2f,
+        Pid::BarometricPressure => 0x33,
+        Pid::ControlModuleVoltage => 0x42,
+        Pid::AmbientTemp => 0x46,
+        Pid::OilTemp => 0x5c,
+        Pid::FuelRate => 0x5e
+    }
+}
+
+// The input key under which a parameter's value is given.
+pub fn key(pid: Pid) -> &'static str {
+    match pid {
+        Pid::EngineLoad => "OBD_ENGINE_LOAD",
+        Pid::CoolantTemp => "OBD_COOLANT_TEMP",
+        Pid::ShortFuelTrim => "OBD_SHORT_FUEL_TRIM",
+        Pid::LongFuelTrim => "OBD_LONG_FUEL_TRIM",
+        Pid::FuelPressure => "OBD_FUEL_PRESSURE",
+        Pid::IntakePressure => "OBD_INTAKE_PRESSURE",
+        Pid::Rpm => "OBD_RPM",
+        Pid::Speed => "OBD_SPEED",
+        Pid::TimingAdvance => "OBD_TIMING_ADVANCE",
+        Pid::IntakeTemp => "OBD_INTAKE_TEMP",
+        Pid::MafRate => "OBD_MAF_RATE",
+        Pid::ThrottlePosition => "OBD_THROTTLE_POSITION",
+        Pid::RunTime => "OBD_RUN_TIME",
+        Pid::FuelLevel => "OBD_FUEL_LEVEL",
+        Pid::BarometricPressure => "OBD_BAROMETRIC_PRESSURE",
+        Pid::ControlModuleVoltage => "OBD_CONTROL_MODULE_VOLTAGE",
+        Pid::AmbientTemp => "OBD_AMBIENT_TEMP",
+        Pid::OilTemp => "OBD_OIL_TEMP",
+        Pid::FuelRate => "OBD_FUEL_RATE"
+    }
+}
+
+// Decode a parameter from the data bytes of its response, using the
+// formula given by the standard, or return `None` if there are too few.
+pub fn decode(pid: Pid, data: &[u8]) -> Option<Float> {
+    let a = *data.first()? as Float;
+    let ab = || Some(a * 256.0 + *data.get(1)? as Float);
+
+    Some(match pid {
+        // Percent.
+        Pid::EngineLoad | Pid::ThrottlePosition | Pid::FuelLevel =>
+            a * 100.0 / 255.0,
+        Pid::ShortFuelTrim | Pid::LongFuelTrim => a * 100.0 / 128.0 - 100.0,
+        // Degrees Celsius.
+        Pid::CoolantTemp | Pid::IntakeTemp | Pid::AmbientTemp | Pid::OilTemp =>
+            a - 40.0,
+        // Kilopascals.
+        Pid::FuelPressure => a * 3.0,
+        Pid::IntakePressure | Pid::BarometricPressure => a,
+        Pid::Rpm => ab()? / 4.0,
+        // Kilometers per hour.
+        Pid::Speed => a,
+        // Degrees before top dead center.
+        Pid::TimingAdvance => a / 2.0 - 64.0,
+        // Grams per second.
+        Pid::MafRate => ab()? / 100.0,
+        // Seconds.
+        Pid::RunTime => ab()?,
+        // Volts.
+        Pid::ControlModuleVoltage => ab()? / 1000.0,
+        // Liters per hour.
+        Pid::FuelRate => ab()? / 20.0
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    Data(Vec<u8>),
+    NoData,
+    // Data for another parameter, which must be the late reply to an
+    // earlier request.
+    Stale,
+    // Anything else, such as `?` or `UNABLE TO CONNECT`.
+    Other(String)
+}
+
+// Parse the adapter's reply to a request for the given code. With
+// several ECUs, there may be several responses, of which the first is
+// used.
+pub fn parse_reply(text: &str, code: u8) -> Reply {
+    let mut stale = false;
+
+    for line in text.split(&['\r', '\n'][..]).map(str::trim) {
+        if line.contains("NO DATA") {
+            return Reply::NoData;
+        }
+
+        // Line noise can put anything into a reply, so check it's hex
+        // before slicing it up.
+        let hex: String = line.split_whitespace().collect();
+        if hex.is_empty() || hex.len() % 2 == 1 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            continue;
+        }
+
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect::<Option<Vec<u8>>>();
+
+        if let Some(bytes) = bytes {
+            if bytes.len() >= 2 && bytes[0] == 0x41 {
+                if bytes[1] == code {
+                    return Reply::Data(bytes[2..].to_vec());
+                }
+                stale = true;
+            }
+        }
+    }
+
+    if stale {
+        Reply::Stale
+    } else {
+        Reply::Other(String::from(text.trim()))
+    }
+}
+
+#[derive(Debug)]
+enum Error {
+    // The adapter didn't finish answering in time.
+    Timeout,
+    // The device has gone away.
+    Closed
+}
+
+// Read from a stream on a thread of its own, so that reads can time out.
+fn spawn_reader<R: Read + Send + 'static>(mut src: R) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = channel();
+
+    spawn(move || {
+        let mut buffer = [0; 256];
+        loop {
+            match src.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => if sender.send(buffer[..len].to_vec()).is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    receiver
+}
+
+// A connection to an ELM327 adapter.
+struct Elm<W: Write> {
+    writer: W,
+    reader: Receiver<Vec<u8>>,
+    // Bytes received after the last prompt.
+    buffer: Vec<u8>,
+    timeout: Duration
+}
+
+impl<W: Write> Elm<W> {
+    fn send(&mut self, command: &str) -> Result<(), Error> {
+        self.writer
+            .write_all(format!("{}\r", command).as_bytes())
+            .and_then(|_| self.writer.flush())
+            .map_err(|_| Error::Closed)
+    }
+
+    // Send a command, and return the reply, up to the adapter's `>`
+    // prompt.
+    fn command(&mut self, command: &str) -> Result<String, Error> {
+        self.send(command)?;
+        self.receive(Instant::now() + self.timeout)
+    }
+
+    // Return the next reply, up to the adapter's `>` prompt.
+    fn receive(&mut self, deadline: Instant) -> Result<String, Error> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|b| *b == b'>') {
+                let reply: Vec<u8> = self.buffer.drain(..=end).collect();
+                return Ok(String::from_utf8_lossy(&reply[..end]).into_owned());
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::Timeout);
+            }
+
+            match self.reader.recv_timeout(deadline - now) {
+                Ok(bytes) => self.buffer.extend(bytes),
+                Err(RecvTimeoutError::Timeout) => return Err(Error::Timeout),
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Closed)
+            }
+        }
+    }
+
+    // Discard input until the adapter has been quiet for `QUIET`, so
+    // that late replies to earlier commands aren't taken as replies to
+    // later ones.
+    fn settle(&mut self) -> Result<(), Error> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self.reader.recv_timeout(QUIET) {
+                Ok(_) if Instant::now() >= deadline => return Err(Error::Timeout),
+                Ok(_) => (),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(Error::Closed)
+            }
+        }
+
+        self.buffer.clear();
+        Ok(())
+    }
+
+    // Reset the adapter, discarding anything left of earlier replies.
+    fn init(&mut self) -> Result<(), Error> {
+        self.settle()?;
+
+        for command in &INIT {
+            self.command(command)?;
+            // The reset may have been answered by a late prompt, so
+            // wait for its own reply to pass too.
+            if *command == "ATZ" {
+                self.settle()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Request a parameter, skipping any late replies to earlier
+    // requests.
+    fn query(&mut self, pid: Pid) -> Result<Reply, Error> {
+        let code = code(pid);
+        let deadline = Instant::now() + self.timeout;
+        self.send(&format!("01{:02X}", code))?;
+
+        loop {
+            match parse_reply(&self.receive(deadline)?, code) {
+                Reply::Stale => continue,
+                reply => return Ok(reply)
+            }
+        }
+    }
+}
+
+// Poll the adapter until the device goes away.
+fn poll<W: Write>(mut elm: Elm<W>, obd: &Obd, sink: &Shared, input: &Input) {
+    let interval = 1.0 / obd.rate.unwrap_or(10.0);
+    let mut ready = false;
+
+    loop {
+        if !ready {
+            match elm.init() {
+                Ok(()) => {
+                    ready = true;
+                    sink.restart();
+                },
+                Err(Error::Timeout) => {
+                    if input.log_errors {
+                        eprintln!("OBD adapter not answering.");
+                    }
+                    sleep(RETRY);
+                    continue;
+                },
+                Err(Error::Closed) => break
+            }
+        }
+
+        for pid in &obd.pids {
+            let start = sink.now();
+
+            match elm.query(*pid) {
+                Ok(Reply::Data(data)) => match decode(*pid, &data) {
+                    Some(value) => {
+                        let mut values = HashMap::new();
+                        values.insert(String::from(key(*pid)), value);
+                        let now = sink.now();
+                        sink.merge(Sample {values, time: now, received: now});
+                    },
+                    None => sink.malformed()
+                },
+                Ok(Reply::NoData) => if input.log_errors {
+                    eprintln!("No data for {:?}", pid);
+                },
+                // Skipped by `query`.
+                Ok(Reply::Stale) => (),
+                Ok(Reply::Other(reply)) => {
+                    if input.log_errors {
+                        eprintln!("Unexpected reply for {:?}: {}", pid, reply);
+                    }
+                    sink.malformed();
+                },
+                Err(Error::Timeout) => {
+                    if input.log_errors {
+                        eprintln!("No reply for {:?}; resetting adapter.", pid);
+                    }
+                    ready = false;
+                    sink.end();
+                    break;
+                },
+                Err(Error::Closed) => return
+            }
+
+            let wait = start + interval - sink.now();
+            if wait > 0.0 {
+                sleep(Duration::from_millis((wait * 1000.0) as u64));
+            }
+        }
+    }
+}
+
+fn nix_error(error: nix::Error) -> io::Error {
+    io::Error::other(error.to_string())
+}
+
+// Open a serial device in raw mode, at the given speed.
+pub(crate) fn open_serial(path: &str, baud: u32) -> io::Result<File> {
+    let baud = match baud {
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115_200 => BaudRate::B115200,
+        230_400 => BaudRate::B230400,
+        _ => return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported baud rate: {}", baud)
+        ))
+    };
+
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let fd = file.as_raw_fd();
+    let mut termios = tcgetattr(fd).map_err(nix_error)?;
+    cfmakeraw(&mut termios);
+    cfsetspeed(&mut termios, baud).map_err(nix_error)?;
+    tcsetattr(fd, SetArg::TCSANOW, &termios).map_err(nix_error)?;
+
+    Ok(file)
+}
+
+// Poll the adapter on the given serial device.
+pub fn source(path: &str, config: &Config) -> io::Result<ThreadedSource> {
+    let obd = config.obd.clone().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "No OBD parameters are configured"
+    ))?;
+
+    let device = open_serial(path, obd.baud.unwrap_or(38400))?;
+    Ok(from_stream(device.try_clone()?, device, obd, config))
+}
+
+// Poll an adapter connected by the given streams.
+fn from_stream<R, W>(
+    src: R,
+    dest: W,
+    obd: Obd,
+    config: &Config
+) -> ThreadedSource
+where R: Read + Send + 'static, W: Write + Send + 'static {
+    let elm = Elm {
+        writer: dest,
+        reader: spawn_reader(src),
+        buffer: Vec::new(),
+        timeout: Duration::from_millis((obd.timeout.unwrap_or(5.0) * 1000.0) as u64)
+    };
+
+    // Until the adapter answers, there's no data.
+    ThreadedSource::connecting(config, move |sink, input| {
+        poll(elm, &obd, sink, input)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+    use crate::testing::wait_for;
+
+    // Answer like an ELM327, with a car which doesn't report coolant
+    // temperature, and is too slow answering the first request for
+    // speed.
+    fn emulate(stream: UnixStream) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut ignored = false;
+
+        for command in BufReader::new(stream).split(b'\r') {
+            let command = String::from_utf8(command.unwrap()).unwrap();
+            let reply = match command.as_str() {
+                "ATZ" => "\r\rELM327 v1.5\r\r",
+                "010C" => "SEARCHING...\r41 0C 1A F8 \r\r",
+                "0105" => "NO DATA\r\r",
+                "010D" if !ignored => {
+                    ignored = true;
+                    sleep(Duration::from_millis(300));
+                    "41 0D 32 \r\r"
+                },
+                "010D" => "41 0D 64 \r41 0D 63 \r\r",
+                _ if command.starts_with("AT") => "OK\r\r",
+                _ => "?\r\r"
+            };
+            write!(writer, "{}>", reply).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(Pid::Rpm, &[0x1a, 0xf8]), Some(1726.0));
+        assert_eq!(decode(Pid::Rpm, &[0x1a]), None);
+        assert_eq!(decode(Pid::CoolantTemp, &[0x7b]), Some(83.0));
+        assert_eq!(decode(Pid::ShortFuelTrim, &[0x80]), Some(0.0));
+        assert_eq!(decode(Pid::ControlModuleVoltage, &[0x36, 0xb0]), Some(14.0));
+        assert_eq!(decode(Pid::TimingAdvance, &[0x94]), Some(10.0));
+
+        assert_eq!(parse_reply("SEARCHING...\r41 0C 1A F8 \r\r", 0x0c), Reply::Data(vec! {0x1a, 0xf8}));
+        assert_eq!(parse_reply("410D64", 0x0d), Reply::Data(vec! {0x64}));
+        assert_eq!(parse_reply("NO DATA\r", 0x05), Reply::NoData);
+        assert_eq!(parse_reply("41 0C 1A F8", 0x0d), Reply::Stale);
+        assert_eq!(parse_reply("OK", 0x0d), Reply::Other(String::from("OK")));
+        assert_eq!(parse_reply("41\u{fffd}C1A", 0x0c), Reply::Other(String::from("41\u{fffd}C1A")));
+    }
+
+    #[test]
+    fn test_polling() {
+        let (adapter, car) = UnixStream::pair().unwrap();
+        spawn(move || emulate(car));
+
+        let obd = Obd {
+            pids: vec! {Pid::Rpm, Pid::CoolantTemp, Pid::Speed},
+            rate: Some(100.0),
+            timeout: Some(0.2),
+            baud: None
+        };
+        let source = from_stream(
+            adapter.try_clone().unwrap(),
+            adapter,
+            obd,
+            &Config::empty()
+        );
+
+        // Speed is read once the adapter has been reset, after the
+        // request which was answered too late.
+        let speed = String::from("OBD_SPEED");
+        let state = wait_for(&source, |s| s.get(&speed).is_some());
+
+        assert_eq!(state.get(&speed), Some(100.0));
+        assert_eq!(state.get(&String::from("OBD_RPM")), Some(1726.0));
+        assert_eq!(state.get(&String::from("OBD_COOLANT_TEMP")), None);
+        assert!(!state.source_lost);
+    }
+}
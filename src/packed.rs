@@ -0,0 +1,534 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// MessagePack and CBOR input
+//
+// Each sample is a map, preceded by its length in bytes, as a
+// big-endian u32. They're read just like JSON samples: keys are channel
+// names, values which aren't numbers are rejected, and the `timestamp`
+// key, if configured, gives the time of the sample.
+//
+// A length over `MAX_LENGTH`, or one not followed by the start of a
+// map, means the framing has been lost, so the reader skips ahead a
+// byte at a time until it finds a frame again.
+
+use std::{
+    collections::HashMap,
+    io::Read
+};
+
+use crate::config::{Float, Input};
+use crate::data::{stamp, Shared};
+
+// Longest sample we accept.
+const MAX_LENGTH: usize = 1 << 16;
+
+// How deeply arrays and maps may nest.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    MessagePack,
+    Cbor
+}
+
+// The encoding of a map, given its first byte, if it's the first byte
+// of a map in either encoding.
+pub fn map_encoding(byte: u8) -> Option<Encoding> {
+    match byte {
+        0x80..=0x8f | 0xde | 0xdf => Some(Encoding::MessagePack),
+        0xa0..=0xbf => Some(Encoding::Cbor),
+        _ => None
+    }
+}
+
+// The parts of a decoded item we care about.
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Number(Float),
+    Text(String),
+    Map(Vec<(Item, Item)>),
+    // Anything else: nil, booleans, byte strings, arrays, extensions.
+    Other
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    // The buffer ends partway through the item.
+    Incomplete,
+    Invalid
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+struct Reader<'a> {
+    buffer: &'a [u8],
+    position: usize
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: u64) -> Result<&'a [u8]> {
+        let end = (self.position as u64).saturating_add(length);
+        if end > self.buffer.len() as u64 {
+            return Err(Error::Incomplete);
+        }
+        let bytes = &self.buffer[self.position..end as usize];
+        self.position = end as usize;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // An unsigned big-endian integer of the given number of bytes.
+    fn uint(&mut self, length: u64) -> Result<u64> {
+        Ok(self.bytes(length)?.iter().fold(0, |n, b| (n << 8) | *b as u64))
+    }
+
+    fn text(&mut self, length: u64) -> Result<Item> {
+        match String::from_utf8(self.bytes(length)?.to_vec()) {
+            Ok(text) => Ok(Item::Text(text)),
+            Err(_) => Err(Error::Invalid)
+        }
+    }
+
+    fn skip(&mut self, length: u64) -> Result<Item> {
+        self.bytes(length)?;
+        Ok(Item::Other)
+    }
+
+    fn msgpack_array(&mut self, length: u64, depth: usize) -> Result<Item> {
+        for _ in 0..length {
+            self.msgpack(depth + 1)?;
+        }
+        Ok(Item::Other)
+    }
+
+    fn msgpack_map(&mut self, length: u64, depth: usize) -> Result<Item> {
+        let mut entries = Vec::new();
+        for _ in 0..length {
+            entries.push((self.msgpack(depth + 1)?, self.msgpack(depth + 1)?));
+        }
+        Ok(Item::Map(entries))
+    }
+
+    fn msgpack(&mut self, depth: usize) -> Result<Item> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Invalid);
+        }
+
+        let byte = self.byte()?;
+        match byte {
+            0x00..=0x7f => Ok(Item::Number(byte as Float)),
+            0x80..=0x8f => self.msgpack_map((byte & 0x0f) as u64, depth),
+            0x90..=0x9f => self.msgpack_array((byte & 0x0f) as u64, depth),
+            0xa0..=0xbf => self.text((byte & 0x1f) as u64),
+            0xc0 | 0xc2 | 0xc3 => Ok(Item::Other),
+            0xc1 => Err(Error::Invalid),
+            0xc4..=0xc6 => {
+                let length = self.uint(1 << (byte - 0xc4))?;
+                self.skip(length)
+            },
+            0xc7..=0xc9 => {
+                let length = self.uint(1 << (byte - 0xc7))?;
+                self.skip(length + 1)
+            },
+            0xca => Ok(Item::Number(f32::from_bits(self.uint(4)? as u32) as Float)),
+            0xcb => Ok(Item::Number(f64::from_bits(self.uint(8)?) as Float)),
+            0xcc..=0xcf => Ok(Item::Number(self.uint(1 << (byte - 0xcc))? as Float)),
+            0xd0..=0xd3 => {
+                let length = 1 << (byte - 0xd0);
+                // Sign extend from the width of the integer.
+                let shift = 64 - 8 * length;
+                let n = ((self.uint(length)? << shift) as i64) >> shift;
+                Ok(Item::Number(n as Float))
+            },
+            0xd4..=0xd8 => self.skip(1 + (1 << (byte - 0xd4))),
+            0xd9..=0xdb => {
+                let length = self.uint(1 << (byte - 0xd9))?;
+                self.text(length)
+            },
+            0xdc | 0xdd => {
+                let length = self.uint(2 << (byte - 0xdc))?;
+                self.msgpack_array(length, depth)
+            },
+            0xde | 0xdf => {
+                let length = self.uint(2 << (byte - 0xde))?;
+                self.msgpack_map(length, depth)
+            },
+            0xe0..=0xff => Ok(Item::Number(byte as i8 as Float))
+        }
+    }
+
+    // The argument of a CBOR item: its value, length, or count, or
+    // `None` if the length is indefinite.
+    fn cbor_argument(&mut self, info: u8) -> Result<Option<u64>> {
+        match info {
+            0..=23 => Ok(Some(info as u64)),
+            24..=27 => Ok(Some(self.uint(1 << (info - 24))?)),
+            31 => Ok(None),
+            _ => Err(Error::Invalid)
+        }
+    }
+
+    // True, and skips it, if the next byte ends an indefinite length
+    // item.
+    fn cbor_break(&mut self) -> Result<bool> {
+        match self.buffer.get(self.position) {
+            Some(0xff) => {
+                self.position += 1;
+                Ok(true)
+            },
+            Some(_) => Ok(false),
+            None => Err(Error::Incomplete)
+        }
+    }
+
+    // The chunks of a byte or text string, of the given major type.
+    fn cbor_string(&mut self, major: u8, length: Option<u64>) -> Result<Vec<u8>> {
+        match length {
+            Some(length) => Ok(self.bytes(length)?.to_vec()),
+            None => {
+                let mut string = Vec::new();
+                while !self.cbor_break()? {
+                    let byte = self.byte()?;
+                    if byte >> 5 != major {
+                        return Err(Error::Invalid);
+                    }
+                    match self.cbor_argument(byte & 0x1f)? {
+                        Some(length) => string.extend(self.bytes(length)?),
+                        None => return Err(Error::Invalid)
+                    }
+                }
+                Ok(string)
+            }
+        }
+    }
+
+    fn cbor(&mut self, depth: usize) -> Result<Item> {
+        if depth > MAX_DEPTH {
+            return Err(Error::Invalid);
+        }
+
+        let byte = self.byte()?;
+        let (major, info) = (byte >> 5, byte & 0x1f);
+        if major == 7 {
+            return match info {
+                0..=24 => self.skip(if info == 24 {1} else {0}),
+                25 => Ok(Item::Number(half(self.uint(2)? as u16))),
+                26 => Ok(Item::Number(f32::from_bits(self.uint(4)? as u32) as Float)),
+                27 => Ok(Item::Number(f64::from_bits(self.uint(8)?) as Float)),
+                _ => Err(Error::Invalid)
+            };
+        }
+
+        let argument = self.cbor_argument(info)?;
+        match (major, argument) {
+            (0, Some(n)) => Ok(Item::Number(n as Float)),
+            (1, Some(n)) => Ok(Item::Number(-1.0 - n as Float)),
+            (2, length) => {
+                self.cbor_string(major, length)?;
+                Ok(Item::Other)
+            },
+            (3, length) => match String::from_utf8(self.cbor_string(major, length)?) {
+                Ok(text) => Ok(Item::Text(text)),
+                Err(_) => Err(Error::Invalid)
+            },
+            (4, Some(length)) => {
+                for _ in 0..length {
+                    self.cbor(depth + 1)?;
+                }
+                Ok(Item::Other)
+            },
+            (4, None) => {
+                while !self.cbor_break()? {
+                    self.cbor(depth + 1)?;
+                }
+                Ok(Item::Other)
+            },
+            (5, Some(length)) => {
+                let mut entries = Vec::new();
+                for _ in 0..length {
+                    entries.push((self.cbor(depth + 1)?, self.cbor(depth + 1)?));
+                }
+                Ok(Item::Map(entries))
+            },
+            (5, None) => {
+                let mut entries = Vec::new();
+                while !self.cbor_break()? {
+                    entries.push((self.cbor(depth + 1)?, self.cbor(depth + 1)?));
+                }
+                Ok(Item::Map(entries))
+            },
+            // A tagged item, such as a date, is read as the item.
+            (6, Some(_)) => self.cbor(depth + 1),
+            _ => Err(Error::Invalid)
+        }
+    }
+}
+
+// Convert a half precision float.
+fn half(bits: u16) -> Float {
+    let sign = if bits & 0x8000 == 0 {1.0} else {-1.0};
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x3ff) as Float;
+    sign * match exponent {
+        0 => mantissa * (2.0 as Float).powi(-24),
+        31 if mantissa == 0.0 => Float::INFINITY,
+        31 => Float::NAN,
+        _ => (1.0 + mantissa / 1024.0) * (2.0 as Float).powi(exponent - 15)
+    }
+}
+
+// Decode the item at the start of the buffer, returning it and the
+// number of bytes it took.
+pub fn decode(encoding: Encoding, buffer: &[u8]) -> Result<(Item, usize)> {
+    let mut reader = Reader {buffer, position: 0};
+    let item = match encoding {
+        Encoding::MessagePack => reader.msgpack(0)?,
+        Encoding::Cbor => reader.cbor(0)?
+    };
+    Ok((item, reader.position))
+}
+
+// Merge a decoded item into the shared state.
+fn merge_item(item: Item, sink: &Shared, input: &Input) {
+    let entries = match item {
+        Item::Map(entries) => entries,
+        item => {
+            if input.log_errors {
+                eprintln!("Not a map: {:?}", item);
+            }
+            sink.malformed();
+            return;
+        }
+    };
+
+    let mut values = HashMap::new();
+    let mut rejected = Vec::new();
+    for (key, value) in entries {
+        match (key, value) {
+            (Item::Text(key), Item::Number(value)) => {values.insert(key, value);},
+            (Item::Text(key), _) => rejected.push(key),
+            (key, _) => rejected.push(format!("{:?}", key))
+        }
+    }
+
+    if input.log_errors && !rejected.is_empty() {
+        eprintln!("Non-numeric values for {:?}", rejected);
+    }
+    sink.rejected(rejected.len());
+
+    match stamp(values, sink.now(), &input.timestamp) {
+        Some(sample) => sink.merge(sample),
+        None => {
+            if input.log_errors {
+                eprintln!("No timestamp");
+            }
+            sink.malformed();
+        }
+    }
+}
+
+enum Frame {
+    Incomplete,
+    // The item, and the length of the frame.
+    Valid(Item, usize),
+    // Not the start of a frame.
+    Invalid
+}
+
+// Decode the frame at the start of the buffer.
+fn frame(encoding: Encoding, buffer: &[u8]) -> Frame {
+    let length = match buffer.get(..4) {
+        Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize,
+        None => return Frame::Incomplete
+    };
+    if length == 0 || length > MAX_LENGTH {
+        return Frame::Invalid;
+    }
+
+    // Check the first byte as soon as we have it, so that a bad length
+    // doesn't leave us waiting for it.
+    match buffer.get(4) {
+        Some(byte) if map_encoding(*byte) != Some(encoding) => return Frame::Invalid,
+        Some(_) => (),
+        None => return Frame::Incomplete
+    }
+
+    let body = match buffer.get(4..4 + length) {
+        Some(body) => body,
+        None => return Frame::Incomplete
+    };
+    match decode(encoding, body) {
+        Ok((item, used)) if used == length => Frame::Valid(item, 4 + length),
+        _ => Frame::Invalid
+    }
+}
+
+// Merge each map in a stream into the shared state, until the end of
+// the stream, or an I/O error.
+pub(crate) fn read_maps<R: Read>(
+    mut src: R,
+    encoding: Encoding,
+    sink: &Shared,
+    input: &Input
+) {
+    let mut buffer = Vec::new();
+    // Position in the buffer of the first byte not yet decoded.
+    let mut start = 0;
+    let mut chunk = [0; 4096];
+    // Skipping invalid bytes, which have already been reported.
+    let mut skipping = false;
+
+    loop {
+        match src.read(&mut chunk) {
+            Ok(0) => {
+                if start < buffer.len() {
+                    if input.log_errors {
+                        eprintln!("Incomplete sample at end of input.");
+                    }
+                    sink.malformed();
+                }
+                if input.log_errors {
+                    eprintln!("End of input.");
+                }
+                break;
+            },
+            Ok(length) => {
+                buffer.drain(..start);
+                start = 0;
+                buffer.extend(&chunk[..length]);
+            },
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading input: {}", e);
+                }
+                break;
+            }
+        }
+
+        loop {
+            match frame(encoding, &buffer[start..]) {
+                Frame::Incomplete => break,
+                Frame::Valid(item, length) => {
+                    start += length;
+                    skipping = false;
+                    merge_item(item, sink, input);
+                },
+                Frame::Invalid => {
+                    start += 1;
+                    if !skipping {
+                        skipping = true;
+                        if input.log_errors {
+                            eprintln!("Skipping invalid input.");
+                        }
+                        sink.malformed();
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::data::{read_input, State};
+
+    fn text(text: &str) -> Item {
+        Item::Text(String::from(text))
+    }
+
+    // Frame a map with its length.
+    fn framed(map: &[u8]) -> Vec<u8> {
+        let mut frame = (map.len() as u32).to_be_bytes().to_vec();
+        frame.extend(map);
+        frame
+    }
+
+    #[test]
+    fn test_decode() {
+        // {"RPM": 3000, "ECT": 90.5, "GEAR": "N", "OIL": -3}
+        let msgpack = [
+            0x84,
+            0xa3, b'R', b'P', b'M', 0xcd, 0x0b, 0xb8,
+            0xa3, b'E', b'C', b'T', 0xcb, 0x40, 0x56, 0xa0, 0, 0, 0, 0, 0,
+            0xa4, b'G', b'E', b'A', b'R', 0xa1, b'N',
+            0xa3, b'O', b'I', b'L', 0xfd
+        ];
+        // The same, as an indefinite length map, with a half float.
+        let cbor = [
+            0xbf,
+            0x63, b'R', b'P', b'M', 0x19, 0x0b, 0xb8,
+            0x63, b'E', b'C', b'T', 0xf9, 0x55, 0xa8,
+            0x64, b'G', b'E', b'A', b'R', 0x61, b'N',
+            0x63, b'O', b'I', b'L', 0x22,
+            0xff
+        ];
+        let expected = Item::Map(vec! {
+            (text("RPM"), Item::Number(3000.0)),
+            (text("ECT"), Item::Number(90.5)),
+            (text("GEAR"), text("N")),
+            (text("OIL"), Item::Number(-3.0))
+        });
+
+        assert_eq!(decode(Encoding::MessagePack, &msgpack), Ok((expected, msgpack.len())));
+        assert_eq!(decode(Encoding::MessagePack, &msgpack[..10]), Err(Error::Incomplete));
+        assert_eq!(decode(Encoding::MessagePack, &[0xc1]), Err(Error::Invalid));
+
+        let (item, length) = decode(Encoding::Cbor, &cbor).unwrap();
+        assert_eq!(length, cbor.len());
+        assert_eq!(item, decode(Encoding::MessagePack, &msgpack).unwrap().0);
+        assert_eq!(decode(Encoding::Cbor, &cbor[..cbor.len() - 1]), Err(Error::Incomplete));
+        assert_eq!(decode(Encoding::Cbor, &[0xff]), Err(Error::Invalid));
+    }
+
+    #[test]
+    fn test_read_maps() {
+        // {"RPM": 3000}, garbage, {"RPM": 3500, "SPEED": true}
+        let mut msgpack = framed(&[0x81, 0xa3, b'R', b'P', b'M', 0xcd, 0x0b, 0xb8]);
+        msgpack.extend(&[0xff, 0xff]);
+        msgpack.extend(framed(&[
+            0x82, 0xa3, b'R', b'P', b'M', 0xcd, 0x0d, 0xac,
+            0xa5, b'S', b'P', b'E', b'E', b'D', 0xc3
+        ]));
+
+        // The format is detected from the start of the first map.
+        let sink = Shared::new(State::new());
+        read_input(&msgpack[..], &sink, &Config::empty().input);
+
+        let state = sink.snapshot(&[]);
+        assert_eq!(state.get(&String::from("RPM")), Some(3500.0));
+        assert_eq!(sink.counters().merged(), 2);
+        assert_eq!(sink.counters().malformed(), 1);
+        assert_eq!(sink.counters().rejected(), 1);
+
+        // {"RPM": 4000}, after a frame whose length is wrong.
+        let mut cbor = vec! {0, 0, 0, 9, 0xa1, 0x63, b'R', b'P', b'M', 0x19, 0x0f, 0xa0};
+        cbor.extend(framed(&[0xa1, 0x63, b'R', b'P', b'M', 0x19, 0x0f, 0xa0]));
+        let sink = Shared::new(State::new());
+        read_input(&cbor[..], &sink, &Config::empty().input);
+        assert_eq!(sink.snapshot(&[]).get(&String::from("RPM")), Some(4000.0));
+        assert_eq!(sink.counters().merged(), 1);
+        assert_eq!(sink.counters().malformed(), 1);
+    }
+}
@@ -0,0 +1,714 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Channel processing
+//
+// Maps the raw values in each sample onto channel values, as samples
+// are merged into the state.
+//
+// A channel reads the input value named by its `input` field, or by
+// its name, and applies its calibration function, if any. Several
+// channels may read the same input. Inputs which no channel reads are
+// passed through unchanged.
+//
+// A channel's filters are applied to the calibrated value, using the
+// sample timestamps, and the unfiltered value is kept alongside it
+// under `<name>.raw`.
+//
+// Time channels are then updated from the channels in the sample, and
+// integrals which are to be persisted are saved at most once a second
+// of sample time.
+//
+// Derived channels are then recomputed from the merged channel values,
+// in the order they are declared, so a derived channel may use those
+// declared before it.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    fs::{self, File},
+    io::{self, Write},
+    path::Path
+};
+
+use crate::ast;
+use crate::ast::{BinOp, UnOp};
+use crate::config::{
+    Channel,
+    Config,
+    Derived,
+    Filter,
+    Float,
+    Function,
+    TimeChannel,
+    TimeFunction
+};
+
+// A filter, and its history.
+#[derive(Debug, Clone)]
+struct FilterState {
+    filter: Filter,
+    // The most recent inputs, for filters over a window of samples.
+    window: VecDeque<Float>,
+    // Time and value of the previous output.
+    last: Option<(Float, Float)>
+}
+
+impl FilterState {
+    fn new(filter: Filter) -> FilterState {
+        FilterState {filter, window: VecDeque::new(), last: None}
+    }
+
+    fn apply(&mut self, value: Float, time: Float) -> Float {
+        let output = match (self.filter, self.last) {
+            (Filter::LowPass(tau), Some((t, y))) => {
+                let alpha = 1.0 - (-(time - t).max(0.0) / tau).exp();
+                y + (value - y) * alpha
+            },
+            (Filter::MovingAverage(n), _) => {
+                self.push(value, n);
+                self.window.iter().sum::<Float>() / self.window.len() as Float
+            },
+            (Filter::Median(n), _) => {
+                self.push(value, n);
+                let mut sorted: Vec<Float> = self.window.iter().cloned().collect();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+                let mid = sorted.len() / 2;
+                if sorted.len() % 2 == 1 {
+                    sorted[mid]
+                } else {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                }
+            },
+            (Filter::RateLimit(rate), Some((t, y))) => {
+                let step = rate * (time - t).max(0.0);
+                y + (value - y).clamp(-step, step)
+            },
+            // The first sample passes through unchanged.
+            (_, None) => value
+        };
+
+        self.last = Some((time, output));
+        output
+    }
+
+    fn push(&mut self, value: Float, len: usize) {
+        self.window.push_back(value);
+        while self.window.len() > len {
+            self.window.pop_front();
+        }
+    }
+}
+
+// A channel reading an input.
+#[derive(Debug, Clone)]
+struct Reader {
+    name: String,
+    calibration: Function,
+    filters: Vec<FilterState>
+}
+
+// Runtime representation of a derived channel's expression.
+//
+// The AST is reference-counted, and so can't be shared with the reader
+// threads, so it is compiled into this simpler form.
+#[derive(Debug, Clone)]
+enum Formula {
+    Const(Float),
+    Chan(String),
+    BinOp(BinOp, Box<Formula>, Box<Formula>),
+    Neg(Box<Formula>),
+    Abs(Box<Formula>)
+}
+
+impl Formula {
+    // Returns `None` for expressions which aren't plain arithmetic.
+    // That includes `Not`, which type checking only allows on booleans,
+    // and so never on channels.
+    fn compile(expr: &ast::Expr) -> Option<Formula> {
+        match expr {
+            ast::Expr::Int(x) => Some(Formula::Const(*x as Float)),
+            ast::Expr::Float(x) => Some(Formula::Const(*x)),
+            ast::Expr::Id(name) => Some(Formula::Chan(name.clone())),
+            ast::Expr::BinOp(op, l, r) => Some(Formula::BinOp(
+                *op,
+                Box::new(Formula::compile(l)?),
+                Box::new(Formula::compile(r)?)
+            )),
+            ast::Expr::UnOp(UnOp::Neg, x) => Some(Formula::Neg(Box::new(Formula::compile(x)?))),
+            ast::Expr::UnOp(UnOp::Abs, x) => Some(Formula::Abs(Box::new(Formula::compile(x)?))),
+            _ => None
+        }
+    }
+
+    // Returns `None` if any channel is missing. Logical operators and
+    // comparisons yield 1.0 for true, and 0.0 for false.
+    fn eval(&self, values: &HashMap<String, Float>) -> Option<Float> {
+        let truth = |x: bool| if x {1.0} else {0.0};
+
+        match self {
+            Formula::Const(x) => Some(*x),
+            Formula::Chan(name) => values.get(name).cloned(),
+            Formula::BinOp(op, l, r) => {
+                let l = l.eval(values)?;
+                let r = r.eval(values)?;
+                Some(match op {
+                    BinOp::Add => l + r,
+                    BinOp::Sub => l - r,
+                    BinOp::Mul => l * r,
+                    BinOp::Div => l / r,
+                    BinOp::Pow => l.powf(r),
+                    BinOp::And => truth(l != 0.0 && r != 0.0),
+                    BinOp::Or  => truth(l != 0.0 || r != 0.0),
+                    BinOp::Xor => truth((l != 0.0) != (r != 0.0)),
+                    BinOp::Lt  => truth(l < r),
+                    BinOp::Gt  => truth(l > r),
+                    BinOp::Lte => truth(l <= r),
+                    BinOp::Gte => truth(l >= r),
+                    BinOp::Eq  => truth(l == r),
+                    BinOp::Shl => l * r.exp2(),
+                    BinOp::Shr => l / r.exp2(),
+                    BinOp::Min => l.min(r),
+                    BinOp::Max => l.max(r)
+                })
+            },
+            Formula::Neg(x) => Some(-x.eval(values)?),
+            Formula::Abs(x) => Some(x.eval(values)?.abs())
+        }
+    }
+}
+
+// A time channel, and its history.
+#[derive(Debug, Clone)]
+struct Tracker {
+    channel: TimeChannel,
+    // Time and value of the previous sample of the source channel.
+    last: Option<(Float, Float)>,
+    // Running total of an integral.
+    total: Float,
+    // Sample time at which the total was last saved.
+    saved: Option<Float>,
+    // Whether the total is due to be written to its file.
+    pending: bool,
+    // Whether saving the total has failed, so it's reported just once.
+    failed: bool
+}
+
+impl Tracker {
+    fn new(channel: &TimeChannel) -> Tracker {
+        let total = match (&channel.function, &channel.persist) {
+            (TimeFunction::Integral(_), Some(path)) => fs::read_to_string(path)
+                .ok()
+                .and_then(|text| text.trim().parse().ok())
+                .unwrap_or(0.0),
+            _ => 0.0
+        };
+
+        Tracker {
+            channel: channel.clone(),
+            last: None,
+            total,
+            saved: None,
+            pending: false,
+            failed: false
+        }
+    }
+
+    // Update with the source channel's value, if it is in the sample.
+    fn update(
+        &mut self,
+        input: Option<Float>,
+        reset: bool,
+        time: Float
+    ) -> Option<Float> {
+        if reset {
+            self.last = None;
+            self.total = 0.0;
+        } else if input.is_none() {
+            return None;
+        }
+
+        let scale = self.channel.scale.unwrap_or(1.0);
+        let output = match (&self.channel.function, input, self.last) {
+            (TimeFunction::Derivative(_), Some(x), Some((t, y))) if time > t =>
+                Some((x - y) / (time - t) * scale),
+            (TimeFunction::Derivative(_), _, _) if reset => Some(0.0),
+            (TimeFunction::Derivative(_), _, _) => None,
+            (TimeFunction::Integral(_), Some(x), Some((t, y))) => {
+                self.total += (x + y) / 2.0 * (time - t).max(0.0) * scale;
+                Some(self.total)
+            },
+            (TimeFunction::Integral(_), _, _) => Some(self.total)
+        };
+
+        if let Some(x) = input {
+            self.last = Some((time, x));
+        }
+
+        let due = match self.saved {
+            Some(t) => time - t >= 1.0,
+            None => true
+        };
+        if reset || due {
+            self.save(time);
+        }
+
+        output
+    }
+
+    // Mark an integral's total as due to be written, if it has a file.
+    fn save(&mut self, time: Float) {
+        if let (TimeFunction::Integral(_), Some(_)) =
+            (&self.channel.function, &self.channel.persist)
+        {
+            self.saved = Some(time);
+            self.pending = true;
+        }
+    }
+
+    // Write the total to its file, if it's due.
+    fn flush(&mut self) {
+        if let (true, Some(path)) = (self.pending, &self.channel.persist) {
+            self.pending = false;
+            if let Err(e) = write_atomic(path, &format!("{}\n", self.total)) {
+                if !self.failed {
+                    eprintln!("Couldn't save {} to {}: {}", self.channel.name, path, e);
+                }
+                self.failed = true;
+            }
+        }
+    }
+}
+
+// Replace the contents of a file, such that a crash leaves either the
+// old contents or the new, rather than a truncated file.
+fn write_atomic(path: &str, text: &str) -> io::Result<()> {
+    let temp = format!("{}.tmp", path);
+    let mut file = File::create(&temp)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+
+    // Make the rename itself durable.
+    let dir = match Path::new(path).parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new(".")
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    // The channels which read each input.
+    inputs: HashMap<String, Vec<Reader>>,
+    // Time channels, in declaration order.
+    trackers: Vec<Tracker>,
+    // Derived channels, in declaration order.
+    derived: Vec<(String, Formula)>
+}
+
+impl Pipeline {
+    pub fn new(
+        channels: &[Channel],
+        time_channels: &[TimeChannel],
+        derived: &[Derived]
+    ) -> Pipeline {
+        let mut inputs: HashMap<String, Vec<Reader>> = HashMap::new();
+
+        for channel in channels {
+            let input = channel.input.as_ref().unwrap_or(&channel.name);
+            let calibration = channel.calibration
+                .clone()
+                .unwrap_or(Function::Identity);
+            let filters = channel.filters
+                .iter()
+                .flatten()
+                .map(|f| FilterState::new(*f))
+                .collect();
+
+            inputs
+                .entry(input.clone())
+                .or_default()
+                .push(Reader {name: channel.name.clone(), calibration, filters});
+        }
+
+        let derived = derived
+            .iter()
+            .filter_map(|d| Some((d.name.clone(), Formula::compile(&d.expr)?)))
+            .collect();
+
+        let trackers = time_channels.iter().map(Tracker::new).collect();
+
+        Pipeline {inputs, trackers, derived}
+    }
+
+    // Create a pipeline for the channels defined in the config.
+    pub fn from_config(config: &Config) -> Pipeline {
+        Pipeline::new(&config.channels, &config.time_channels, &config.derived)
+    }
+
+    // Convert the raw input values of a sample taken at the given time
+    // to channel values.
+    pub fn process(
+        &mut self,
+        values: &HashMap<String, Float>,
+        time: Float
+    ) -> HashMap<String, Float> {
+        let mut ret = HashMap::new();
+
+        for (key, value) in values {
+            let value = *value;
+            match self.inputs.get_mut(key) {
+                Some(channels) => for channel in channels {
+                    let value = channel.calibration.apply(value);
+                    if channel.filters.is_empty() {
+                        ret.insert(channel.name.clone(), value);
+                    } else {
+                        let filtered = channel.filters
+                            .iter_mut()
+                            .fold(value, |v, f| f.apply(v, time));
+                        ret.insert(format!("{}.raw", channel.name), value);
+                        ret.insert(channel.name.clone(), filtered);
+                    }
+                },
+                None => {ret.insert(key.clone(), value);}
+            }
+        }
+
+        ret
+    }
+
+    // Update each time channel from the channel values in the latest
+    // sample, resetting those whose reset channel in `values` is high,
+    // and return the names of those which were updated.
+    pub fn integrate(
+        &mut self,
+        sample: &HashMap<String, Float>,
+        values: &mut HashMap<String, Float>,
+        time: Float
+    ) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        for tracker in &mut self.trackers {
+            let input = sample.get(tracker.channel.function.source()).cloned();
+            let reset = match tracker.channel.reset.as_ref() {
+                Some(name) => values.get(name).cloned().unwrap_or(0.0) != 0.0,
+                None => false
+            };
+
+            if let Some(value) = tracker.update(input, reset, time) {
+                values.insert(tracker.channel.name.clone(), value);
+                ret.push(tracker.channel.name.clone());
+            }
+        }
+
+        ret
+    }
+
+    // Save the totals of persisted integrals, as of the given time.
+    pub fn save(&mut self, time: Float) {
+        for tracker in &mut self.trackers {
+            tracker.save(time);
+        }
+        self.flush();
+    }
+
+    // Write the totals of persisted integrals which are due to be
+    // saved. This is kept apart from `integrate`, so that the file I/O
+    // can be done without holding the state.
+    pub fn flush(&mut self) {
+        for tracker in &mut self.trackers {
+            tracker.flush();
+        }
+    }
+
+    // Recompute each derived channel from the given channel values,
+    // and return the names of those which could be computed.
+    pub fn derive(&self, values: &mut HashMap<String, Float>) -> Vec<String> {
+        let mut ret = Vec::new();
+
+        for (name, formula) in &self.derived {
+            if let Some(value) = formula.eval(values) {
+                values.insert(name.clone(), value);
+                ret.push(name.clone());
+            }
+        }
+
+        ret
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Unit;
+
+    fn sample(values: &[(&str, Float)]) -> HashMap<String, Float> {
+        values.iter().map(|(k, v)| (String::from(*k), *v)).collect()
+    }
+
+    #[test]
+    fn test_calibration() {
+        let ect = Channel {
+            name: String::from("ECT"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: Some(Function::Linear(2.0, 1.0)),
+            filters: None
+        };
+        let oil_temp_raw = Channel {
+            name: String::from("OIL_TEMP_RAW"),
+            input: Some(String::from("AIN1")),
+            calibration: None,
+            ..ect.clone()
+        };
+        let mut pipeline = Pipeline::new(&[
+            ect.clone(),
+            Channel {
+                name: String::from("OIL_PRESSURE"),
+                input: Some(String::from("AIN0")),
+                calibration: Some(Function::Scale(0.5)),
+                ..ect
+            },
+            Channel {
+                name: String::from("OIL_TEMP"),
+                calibration: Some(Function::Polynomial(vec! {1.0, 2.0, 3.0})),
+                ..oil_temp_raw.clone()
+            },
+            oil_temp_raw
+        ], &[], &[]);
+
+        let values = pipeline.process(&sample(&[
+            ("ECT", 100.0),
+            ("AIN0", 90.0),
+            ("AIN1", 2.0),
+            ("RPM", 1500.0)
+        ]), 0.0);
+
+        assert_eq!(values, sample(&[
+            ("ECT", 201.0),
+            ("OIL_PRESSURE", 45.0),
+            ("OIL_TEMP", 17.0),
+            ("OIL_TEMP_RAW", 2.0),
+            ("RPM", 1500.0)
+        ]));
+    }
+
+    #[test]
+    fn test_table() {
+        use crate::config::OutOfRange::*;
+
+        let points = vec! {(0.5, 0.0), (1.5, 10.0), (4.5, 40.0)};
+        let fuel = Channel {
+            name: String::from("FUEL"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: Some(Function::Table(points.clone(), Clamp)),
+            filters: None
+        };
+        let mut pipeline = Pipeline::new(&[
+            fuel.clone(),
+            Channel {
+                name: String::from("FUEL_X"),
+                input: Some(String::from("FUEL")),
+                calibration: Some(Function::Table(points, Extrapolate)),
+                ..fuel
+            }
+        ], &[], &[]);
+
+        for (input, clamped, extrapolated) in &[
+            (0.0, 0.0, -5.0),
+            (0.5, 0.0, 0.0),
+            (1.0, 5.0, 5.0),
+            (3.0, 25.0, 25.0),
+            (4.5, 40.0, 40.0),
+            (5.0, 40.0, 45.0)
+        ] {
+            let values = pipeline.process(&sample(&[("FUEL", *input)]), 0.0);
+            assert_eq!(values[&String::from("FUEL")], *clamped);
+            assert_eq!(values[&String::from("FUEL_X")], *extrapolated);
+        }
+    }
+
+    #[test]
+    fn test_thermistor() {
+        // A 10k NTC thermistor, under a 10k pull-up from 5V.
+        let mut pipeline = Pipeline::new(&[Channel {
+            name: String::from("ECT"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: Some(Function::Chain(vec! {
+                Function::Divider(10000.0, 5.0),
+                Function::Thermistor(1.125308852e-3, 2.347118633e-4, 8.566356096e-8)
+            })),
+            filters: None
+        }], &[], &[]);
+
+        let values = pipeline.process(&sample(&[("ECT", 2.5)]), 0.0);
+        assert!((values[&String::from("ECT")] - 25.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_derived() {
+        use crate::ast::{Expr, Node};
+
+        let chan = |name: &str| Node::new(Expr::Id(String::from(name)));
+        let derived = |name: &str, expr: Expr| Derived {
+            name: String::from(name),
+            units: Unit::None,
+            expr
+        };
+
+        let mut pipeline = Pipeline::new(&[Channel {
+            name: String::from("OIL_PRESSURE"),
+            units: Unit::None,
+            timeout: None,
+            input: Some(String::from("AIN0")),
+            calibration: Some(Function::Scale(0.5)),
+            filters: None
+        }], &[], &[
+            derived("OIL_MARGIN", Expr::BinOp(
+                BinOp::Sub,
+                chan("OIL_PRESSURE"),
+                Node::new(Expr::BinOp(
+                    BinOp::Mul,
+                    chan("RPM"),
+                    Node::new(Expr::Float(0.01))
+                ))
+            )),
+            derived("OIL_OK", Expr::BinOp(
+                BinOp::Gt,
+                chan("OIL_MARGIN"),
+                Node::new(Expr::Float(0.0))
+            ))
+        ]);
+
+        let mut values = pipeline.process(&sample(&[("AIN0", 90.0)]), 0.0);
+        // Nothing can be derived until every input is known.
+        assert!(pipeline.derive(&mut values).is_empty());
+
+        values.insert(String::from("RPM"), 3000.0);
+        assert_eq!(pipeline.derive(&mut values), vec! {
+            String::from("OIL_MARGIN"),
+            String::from("OIL_OK")
+        });
+        assert_eq!(values[&String::from("OIL_MARGIN")], 15.0);
+        assert_eq!(values[&String::from("OIL_OK")], 1.0);
+    }
+
+    #[test]
+    fn test_filters() {
+        let filtered = |filter| Channel {
+            name: String::from("MAP"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: None,
+            filters: Some(vec! {filter})
+        };
+
+        for (filter, expected) in &[
+            (Filter::MovingAverage(3), [10.0, 15.0, 20.0, 30.0, 40.0]),
+            (Filter::Median(3), [10.0, 15.0, 20.0, 30.0, 40.0]),
+            (Filter::RateLimit(5.0), [10.0, 15.0, 20.0, 25.0, 30.0]),
+            // Half way to the input each second.
+            (Filter::LowPass(1.0 / Float::ln(2.0)), [10.0, 15.0, 22.5, 31.25, 40.625])
+        ] {
+            let mut pipeline = Pipeline::new(&[filtered(*filter)], &[], &[]);
+            for (i, (input, output)) in [10.0, 20.0, 30.0, 40.0, 50.0]
+                .iter()
+                .zip(expected.iter())
+                .enumerate()
+            {
+                let values = pipeline.process(&sample(&[("MAP", *input)]), i as Float);
+                assert_eq!(values[&String::from("MAP.raw")], *input);
+                assert!((values[&String::from("MAP")] - output).abs() < 1e-9);
+            }
+        }
+
+        // A median filter rejects an isolated spike.
+        let mut pipeline = Pipeline::new(&[filtered(Filter::Median(3))], &[], &[]);
+        let outputs: Vec<Float> = [10.0, 10.0, 90.0, 10.0]
+            .iter()
+            .map(|x| pipeline.process(&sample(&[("MAP", *x)]), 0.0)[&String::from("MAP")])
+            .collect();
+        assert_eq!(outputs, vec! {10.0, 10.0, 10.0, 10.0});
+    }
+
+    #[test]
+    fn test_time_channels() {
+        use crate::config::TimeFunction::*;
+
+        let path = std::env::temp_dir()
+            .join(format!("udashboard-test-fuel-used-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "100.0\n").unwrap();
+
+        let time_channel = |name: &str, function, persist: Option<&str>| TimeChannel {
+            name: String::from(name),
+            units: Unit::None,
+            function,
+            scale: None,
+            reset: Some(String::from("RESET")),
+            persist: persist.map(String::from)
+        };
+
+        let mut pipeline = Pipeline::new(&[], &[
+            time_channel("RPM_RATE", Derivative(String::from("RPM")), None),
+            time_channel("FUEL_USED", Integral(String::from("FLOW")), Some(path))
+        ], &[]);
+
+        let mut step = |time, sample: HashMap<String, Float>, reset| {
+            let mut values = sample.clone();
+            values.insert(String::from("RESET"), reset);
+            pipeline.integrate(&sample, &mut values, time);
+            pipeline.flush();
+            values
+        };
+
+        // The integral starts from its saved total.
+        let values = step(0.0, sample(&[("RPM", 1000.0), ("FLOW", 2.0)]), 0.0);
+        assert!(!values.contains_key("RPM_RATE"));
+        assert_eq!(values["FUEL_USED"], 100.0);
+
+        let values = step(0.5, sample(&[("RPM", 1500.0), ("FLOW", 4.0)]), 0.0);
+        assert_eq!(values["RPM_RATE"], 1000.0);
+        assert_eq!(values["FUEL_USED"], 101.5);
+
+        // A sample without the source leaves the time channel alone.
+        let values = step(0.75, sample(&[("ECT", 180.0)]), 0.0);
+        assert!(!values.contains_key("FUEL_USED"));
+
+        let values = step(1.0, sample(&[("RPM", 1500.0), ("FLOW", 4.0)]), 0.0);
+        assert_eq!(values["RPM_RATE"], 0.0);
+        assert_eq!(values["FUEL_USED"], 103.5);
+        assert_eq!(fs::read_to_string(path).unwrap().trim(), "103.5");
+
+        let values = step(1.5, sample(&[]), 1.0);
+        assert_eq!(values["RPM_RATE"], 0.0);
+        assert_eq!(values["FUEL_USED"], 0.0);
+        assert_eq!(fs::read_to_string(path).unwrap().trim(), "0");
+        assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// RaceCapture/Pro data source
+//
+// Speaks the RaceCapture JSON API, over USB serial or TCP. On
+// connecting, we ask for the channel metadata, and subscribe to
+// telemetry at the requested rate. The unit then streams `s` messages,
+// each holding the values of some of its channels, in the order given
+// by the metadata, followed by bitmasks saying which channels are
+// present.
+//
+// Each RaceCapture channel's value is given under its own name. In
+// addition, a configured channel without an explicit `input` is
+// matched to the RaceCapture channel of the same name, ignoring case
+// and punctuation, so `OIL_PRESS` reads `OilPress`. If both name
+// their units, and they differ, the value is converted to the
+// channel's units, e.g. from kPa to PSI.
+//
+// If the connection drops, the source is flagged as lost, and we
+// reconnect every second until it succeeds. Since telemetry streams at
+// a known rate, a TCP connection which goes quiet for ten sample
+// periods, or two seconds if that's longer, is taken to have dropped.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    thread::sleep,
+    time::Duration
+};
+
+use serde_json;
+use serde_json::Value;
+
+use crate::config::{Channel, Config, Float, Input, Unit};
+use crate::data::{Sample, Shared, ThreadedSource};
+use crate::obd::open_serial;
+
+// Delay between attempts to connect.
+const RETRY: Duration = Duration::from_secs(1);
+
+// Speed of the RaceCapture's USB serial port.
+const BAUD: u32 = 115_200;
+
+// A RaceCapture channel, as described by its metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Meta {
+    pub name: String,
+    pub units: String
+}
+
+// An input to give a RaceCapture channel's value as.
+#[derive(Debug, Clone, PartialEq)]
+struct Mapping {
+    key: String,
+    scale: Float,
+    offset: Float
+}
+
+// Lower case, without punctuation, for loose comparisons.
+fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+// The scale and offset which convert between two units, if we know
+// how.
+fn conversion(from: &str, to: &str) -> Option<(Float, Float)> {
+    Some(match (normalize(from).as_str(), normalize(to).as_str()) {
+        (from, to) if from == to => (1.0, 0.0),
+        ("kph", "kmh") | ("kmh", "kph") => (1.0, 0.0),
+        ("c", "f") => (1.8, 32.0),
+        ("f", "c") => (5.0 / 9.0, -160.0 / 9.0),
+        ("kpa", "psi") => (0.145_038, 0.0),
+        ("psi", "kpa") => (6.894_76, 0.0),
+        ("bar", "psi") => (14.503_8, 0.0),
+        ("bar", "kpa") => (100.0, 0.0),
+        ("kpa", "bar") => (0.01, 0.0),
+        ("psi", "bar") => (0.068_947_6, 0.0),
+        ("kph", "mph") | ("kmh", "mph") => (0.621_371, 0.0),
+        ("mph", "kph") | ("mph", "kmh") => (1.609_344, 0.0),
+        _ => return None
+    })
+}
+
+// The inputs to give each RaceCapture channel's value as.
+fn mappings(meta: &[Meta], channels: &[Channel]) -> Vec<Vec<Mapping>> {
+    meta.iter().map(|m| {
+        let mut mappings = vec! {
+            Mapping {key: m.name.clone(), scale: 1.0, offset: 0.0}
+        };
+
+        let matching = channels
+            .iter()
+            .filter(|c| c.input.is_none() && normalize(&c.name) == normalize(&m.name));
+
+        for channel in matching {
+            let (scale, offset) = match &channel.units {
+                Unit::Named(units) if !m.units.is_empty() =>
+                    conversion(&m.units, units).unwrap_or_else(|| {
+                        eprintln!(
+                            "Can't convert {} from {} to {}; using it unconverted.",
+                            m.name, m.units, units
+                        );
+                        (1.0, 0.0)
+                    }),
+                _ => (1.0, 0.0)
+            };
+
+            // A channel with exactly the RaceCapture name replaces the
+            // unconverted value.
+            mappings.retain(|mapping| mapping.key != channel.name);
+            mappings.push(Mapping {key: channel.name.clone(), scale, offset});
+        }
+
+        mappings
+    }).collect()
+}
+
+pub fn parse_meta(value: &Value) -> Option<Vec<Meta>> {
+    value.as_array()?.iter().map(|m| Some(Meta {
+        name: String::from(m.get("nm")?.as_str()?),
+        units: String::from(m.get("ut").and_then(|u| u.as_str()).unwrap_or(""))
+    })).collect()
+}
+
+// Parse the `d` field of a sample, given the number of channels, into
+// the index and value of each channel present.
+//
+// The values are followed by one bitmask for every 32 channels, with
+// a bit set for each channel present, least significant first.
+pub fn parse_sample(data: &[Value], count: usize) -> Option<Vec<(usize, Float)>> {
+    let masks = count.div_ceil(32);
+    if data.len() < masks {
+        return None;
+    }
+
+    let (values, masks) = data.split_at(data.len() - masks);
+    let masks = masks
+        .iter()
+        .map(|m| m.as_u64())
+        .collect::<Option<Vec<u64>>>()?;
+
+    let present = (0..count).filter(|i| masks[i / 32] >> (i % 32) & 1 == 1);
+    let present: Vec<usize> = present.collect();
+    if present.len() != values.len() {
+        return None;
+    }
+
+    present
+        .into_iter()
+        .zip(values)
+        .map(|(i, value)| Some((i, value.as_f64()?)))
+        .collect()
+}
+
+// A connection to a RaceCapture unit.
+struct Session<'a> {
+    channels: &'a [Channel],
+    sink: &'a Shared,
+    input: &'a Input,
+    // Inputs for each RaceCapture channel, once we have the metadata.
+    mappings: Vec<Vec<Mapping>>
+}
+
+impl<'a> Session<'a> {
+    fn set_meta(&mut self, meta: &Value) {
+        match parse_meta(meta) {
+            Some(meta) => self.mappings = mappings(&meta, self.channels),
+            None => if self.input.log_errors {
+                eprintln!("Invalid RaceCapture metadata");
+            }
+        }
+    }
+
+    // Handle one message from the unit.
+    fn handle(&mut self, line: &str) {
+        let message: Value = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => {
+                if self.input.log_errors {
+                    eprintln!("Not a RaceCapture message: {}", line);
+                }
+                self.sink.malformed();
+                return;
+            }
+        };
+
+        if let Some(meta) = message.get("meta") {
+            self.set_meta(meta);
+        }
+
+        let sample = match message.get("s") {
+            Some(sample) => sample,
+            // Replies to other requests.
+            None => return
+        };
+
+        if let Some(meta) = sample.get("meta") {
+            self.set_meta(meta);
+        }
+
+        let values = sample
+            .get("d")
+            .and_then(|d| d.as_array())
+            .and_then(|d| parse_sample(d, self.mappings.len()));
+
+        let values = match values {
+            Some(values) => values,
+            None => {
+                if self.input.log_errors {
+                    eprintln!("Invalid RaceCapture sample: {}", line);
+                }
+                self.sink.malformed();
+                return;
+            }
+        };
+
+        let mut sample_values = HashMap::new();
+        for (i, value) in values {
+            for mapping in &self.mappings[i] {
+                sample_values.insert(mapping.key.clone(), value * mapping.scale + mapping.offset);
+            }
+        }
+
+        let now = self.sink.now();
+        self.sink.merge(Sample {values: sample_values, time: now, received: now});
+    }
+}
+
+// Subscribe to telemetry, and read it until the connection drops.
+fn read_session<R: Read, W: Write>(
+    src: R,
+    mut dest: W,
+    rate: u32,
+    channels: &[Channel],
+    sink: &Shared,
+    input: &Input
+) {
+    let subscribe = format!(
+        "{{\"getMeta\":null}}\r\n{{\"setTelemetry\":{{\"rate\":{}}}}}\r\n",
+        rate
+    );
+    if let Err(e) = dest.write_all(subscribe.as_bytes()).and_then(|_| dest.flush()) {
+        if input.log_errors {
+            eprintln!("Error writing to RaceCapture: {}", e);
+        }
+        return;
+    }
+
+    sink.restart();
+    let mut session = Session {channels, sink, input, mappings: Vec::new()};
+
+    for line in BufReader::new(src).lines() {
+        match line {
+            Ok(line) => if !line.trim().is_empty() {
+                session.handle(line.trim());
+            },
+            Err(e) => {
+                if input.log_errors {
+                    eprintln!("Error reading RaceCapture: {}", e);
+                }
+                break;
+            }
+        }
+    }
+
+    sink.end();
+}
+
+// How long telemetry at the given rate may go quiet before the
+// connection is taken to have dropped.
+fn silence(rate: u32) -> Duration {
+    Duration::from_secs_f64((10.0 / rate as f64).max(2.0))
+}
+
+// Connect to a unit, either by a serial device, or by a TCP address
+// such as `192.168.4.1:7223`, and request telemetry at the given rate.
+pub fn source(addr: &str, rate: u32, config: &Config) -> ThreadedSource {
+    let channels = config.channels.clone();
+    let addr = String::from(addr);
+
+    // Until we're connected, there's no data.
+    ThreadedSource::connecting(config, move |sink, input| loop {
+        let result = if addr.starts_with('/') {
+            open_serial(&addr, BAUD).and_then(|device| {
+                let dest = device.try_clone()?;
+                read_session(device, dest, rate, &channels, sink, input);
+                Ok(())
+            })
+        } else {
+            TcpStream::connect(&addr).and_then(|stream| {
+                stream.set_read_timeout(Some(silence(rate)))?;
+                let dest = stream.try_clone()?;
+                read_session(stream, dest, rate, &channels, sink, input);
+                Ok(())
+            })
+        };
+
+        if let Err(e) = result {
+            if input.log_errors {
+                eprintln!("Error connecting to {}: {}", addr, e);
+            }
+        }
+
+        sleep(RETRY);
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::State;
+
+    #[test]
+    fn test_mappings() {
+        let meta = vec! {
+            Meta {name: String::from("RPM"), units: String::new()},
+            Meta {name: String::from("OilPress"), units: String::from("kPa")},
+            Meta {name: String::from("EngineTemp"), units: String::from("C")}
+        };
+        let rpm = Channel {
+            name: String::from("RPM"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: None,
+            filters: None
+        };
+        let channels = vec! {
+            rpm.clone(),
+            Channel {
+                name: String::from("OIL_PRESS"),
+                units: Unit::Named(String::from("PSI")),
+                ..rpm.clone()
+            },
+            Channel {
+                name: String::from("ECT"),
+                units: Unit::Named(String::from("F")),
+                input: Some(String::from("EngineTemp")),
+                ..rpm
+            }
+        };
+
+        let mappings = mappings(&meta, &channels);
+        let keys: Vec<Vec<&str>> = mappings
+            .iter()
+            .map(|m| m.iter().map(|m| m.key.as_str()).collect())
+            .collect();
+        assert_eq!(keys, vec! {vec! {"RPM"}, vec! {"OilPress", "OIL_PRESS"}, vec! {"EngineTemp"}});
+        assert_eq!(mappings[1][1].scale, 0.145_038);
+
+        assert_eq!(conversion("°C", "F"), Some((1.8, 32.0)));
+        assert_eq!(conversion("km/h", "MPH"), Some((0.621_371, 0.0)));
+        assert_eq!(conversion("kPa", "bar"), Some((0.01, 0.0)));
+        assert_eq!(conversion("bar", "bar"), Some((1.0, 0.0)));
+        assert_eq!(conversion("V", "PSI"), None);
+    }
+
+    #[test]
+    fn test_session() {
+        let telemetry = "{\"meta\":[{\"nm\":\"Utc\",\"ut\":\"ms\",\"sr\":10},\
+                                    {\"nm\":\"RPM\",\"ut\":\"\",\"sr\":10},\
+                                    {\"nm\":\"EngineTemp\",\"ut\":\"C\",\"sr\":1}]}\r\n\
+                         {\"s\":{\"t\":100,\"d\":[1571234567000,3000,90,7]}}\r\n\
+                         {\"s\":{\"t\":101,\"d\":[1571234567100,3500,3]}}\r\n\
+                         {\"s\":{\"t\":102,\"d\":[1,2,7]}}\r\n";
+
+        let channels = vec! {Channel {
+            name: String::from("ENGINE_TEMP"),
+            units: Unit::Named(String::from("F")),
+            timeout: None,
+            input: None,
+            calibration: None,
+            filters: None
+        }};
+        let sink = Shared::new(State::new());
+        let mut requests = Vec::new();
+        let input = Config::empty().input;
+        read_session(telemetry.as_bytes(), &mut requests, 50, &channels, &sink, &input);
+
+        assert_eq!(
+            String::from_utf8(requests).unwrap(),
+            "{\"getMeta\":null}\r\n{\"setTelemetry\":{\"rate\":50}}\r\n"
+        );
+
+        let state = sink.snapshot(&[]);
+        assert_eq!(state.get(&String::from("RPM")), Some(3500.0));
+        assert_eq!(state.get(&String::from("EngineTemp")), Some(90.0));
+        assert_eq!(state.get(&String::from("ENGINE_TEMP")), Some(194.0));
+        assert_eq!(sink.counters().merged(), 2);
+        assert_eq!(sink.counters().malformed(), 1);
+        assert!(state.source_lost);
+    }
+}
@@ -17,6 +17,7 @@
 // <https://www.gnu.org/licenses/>.
 
 // Cairo rendering implementation
+use std::cell::Cell;
 use std::fs::File;
 use std::f64::consts::PI;
 
@@ -25,6 +26,7 @@ use cairo::{Context, Format, ImageSurface, LineCap};
 
 use crate::config;
 use crate::config::{
+    Blink,
     Bounds,
     Color,
     // Config,
@@ -48,29 +50,45 @@ use crate::data::State;
 pub struct CairoRenderer {
     screen: Screen,
     pages: Vec<Vec<Gauge>>,
+    alarms: Vec<String>,
     default_style: Style,
-    page: usize
+    slow_blink: Blink,
+    fast_blink: Blink,
+    page: usize,
+    // Time at which the current frame is being rendered.
+    time: Cell<Float>
 }
 
 impl CairoRenderer {
     pub fn new(
         screen: Screen,
         pages: Vec<Vec<Gauge>>,
+        alarms: Vec<String>,
         default_style: Style,
+        slow_blink: Blink,
+        fast_blink: Blink
     ) -> CairoRenderer {
         CairoRenderer {
             screen: screen,
             pages: pages,
+            alarms: alarms,
             default_style: default_style,
-            page: 0
+            slow_blink: slow_blink,
+            fast_blink: fast_blink,
+            page: 0,
+            time: Cell::new(0.0)
         }
     }
 
+    // Render one frame. `time` is the frame time in seconds, which
+    // must increase monotonically, and drives any animation.
     pub fn render(
         &self,
         cr: &Context,
-        state: &State
+        state: &State,
+        time: Float
     ) {
+        self.time.set(time);
         self.set_pattern(cr, &self.default_style.background);
         cr.paint();
 
@@ -79,6 +97,22 @@ impl CairoRenderer {
             self.render_gauge(cr, &gauge, state);
             cr.restore();
         }
+
+        if state.source_lost {
+            self.source_lost(cr);
+        }
+    }
+
+    // Warn that no more data will arrive, across the top of the screen.
+    fn source_lost(&self, cr: &Context) {
+        cr.save();
+        cr.move_to(self.screen.width * 0.5, 36.0);
+        self.center_label(cr, &Label::Styled(
+            String::from("DATA SOURCE LOST"),
+            36.0,
+            Color(1.0, 0.0, 0.0, 1.0)
+        ));
+        cr.restore();
     }
 
     fn set_color(&self, cr: &Context, color: &Color) {
@@ -94,20 +128,101 @@ impl CairoRenderer {
         match pat {
             Pattern::Hidden => {return false},
             Pattern::Solid(c) => self.set_color(cr, c),
-            Pattern::SlowBlink(c) => self.set_color(cr, c),
-            Pattern::FastBlink(c) => self.set_color(cr, c)
+            Pattern::SlowBlink(c) => return self.set_blink(cr, c, &self.slow_blink),
+            Pattern::FastBlink(c) => return self.set_blink(cr, c, &self.fast_blink)
         }
 
         true
     }
 
-    fn get_style(&self, g: &Gauge, _state: &State) -> Style {
-        // XXX: do actual lookup
-        let state = &config::State::Default;
-        let style = g.styles.get(state);
+    // Set the color only during the lit part of the blink cycle.
+    fn set_blink(&self, cr: &Context, color: &Color, blink: &Blink) -> bool {
+        if blink.is_lit(self.time.get()) {
+            self.set_color(cr, color);
+            true
+        } else {
+            false
+        }
+    }
+
+    // Return the style for the active state of highest precedence
+    // which this gauge defines a style for, if any.
+    //
+    // Stale data can't be trusted to raise or clear an alarm, so
+    // stale states take precedence over alarms, starting with the
+    // gauge's own channel. A gauge whose own channel is stale is
+    // greyed out, unless it defines a style for this.
+    fn get_style(&self, g: &Gauge, state: &State) -> Style {
+        if state.stale.contains(&g.channel) {
+            let own = config::State::Stale(g.channel.clone());
+            return match g.styles.get(&own) {
+                Some(style) => *style,
+                None => Self::greyed(self.default_style_for(g))
+            };
+        }
+
+        let mut stale: Vec<&String> = g.styles
+            .keys()
+            .filter_map(|s| match s {
+                config::State::Stale(c) if state.stale.contains(c) => Some(c),
+                _ => None
+            })
+            .collect();
+        stale.sort();
+
+        if let Some(channel) = stale.first() {
+            return g.styles[&config::State::Stale((*channel).clone())];
+        }
+
+        for name in &self.alarms {
+            if state.states.get(name) == Some(&true) {
+                let alarm = config::State::Alarm(name.clone());
+                if let Some(style) = g.styles.get(&alarm) {
+                    return *style;
+                }
+            }
+        }
+
+        self.default_style_for(g)
+    }
+
+    fn default_style_for(&self, g: &Gauge) -> Style {
+        let style = g.styles.get(&config::State::Default);
         *(style).unwrap()
     }
 
+    // Grey out everything but the background of the given style.
+    fn greyed(style: Style) -> Style {
+        let grey = Pattern::Solid(Color(0.5, 0.5, 0.5, 1.0));
+        Style {
+            background: style.background,
+            foreground: grey,
+            indicator: grey
+        }
+    }
+
+    // Return the value of the gauge's channel, unless it is stale.
+    fn value(&self, gauge: &Gauge, state: &State) -> Option<Float> {
+        if state.stale.contains(&gauge.channel) {
+            None
+        } else {
+            state.get(&gauge.channel)
+        }
+    }
+
+    // Return the session extremes marked by the gauge's peak-hold
+    // option, if it has one.
+    fn peaks(&self, gauge: &Gauge, scale: &Scale, state: &State) -> Vec<Float> {
+        match scale.4 {
+            Some(hold) => hold
+                .suffixes()
+                .iter()
+                .filter_map(|s| state.get(&format!("{}.{}", gauge.channel, s)))
+                .collect(),
+            None => Vec::new()
+        }
+    }
+
     fn set_background(&self, cr: &Context, g: &Gauge, state: &State) -> bool {
         self.set_pattern(cr, &self.get_style(g, state).background)
     }
@@ -272,9 +387,24 @@ impl CairoRenderer {
             cr.restore();
         }
 
+        // Render the peak-hold markers.
+        if self.set_indicator(cr, gauge, state) {
+            cr.save();
+            cr.set_line_width(6.0);
+            for peak in self.peaks(gauge, scale, state) {
+                cr.save();
+                cr.rotate(scale.to_angle(peak));
+                cr.move_to(0.0, -radius * 0.99);
+                cr.line_to(0.0, -radius * 0.80);
+                cr.restore();
+            }
+            cr.stroke();
+            cr.restore();
+        }
+
         // Render the indicator.
         if self.set_indicator(cr, gauge, state) {
-            if let Some(value) = state.get(&gauge.channel) {
+            if let Some(value) = self.value(gauge, state) {
                 cr.rotate(scale.to_angle(value).into());
                 cr.move_to(-10.0, 0.0);
                 cr.rel_line_to(-1.5, -radius * 0.99);
@@ -302,7 +432,7 @@ impl CairoRenderer {
         self.show_outline(cr, gauge, scale.3, state);
 
         if self.set_indicator(cr, gauge, state) {
-            if let Some(value) = state.get(&gauge.channel) {
+            if let Some(value) = self.value(gauge, state) {
                 let bounds = bounds.inset(1.0);
                 cr.save();
                 let fill = bounds.height * (1.0 - scale.to_percent(value));
@@ -318,6 +448,16 @@ impl CairoRenderer {
                 cr.fill();
                 cr.restore();
             }
+
+            cr.save();
+            cr.set_line_width(3.0);
+            for peak in self.peaks(gauge, scale, state) {
+                let y = bounds.y + bounds.height * (1.0 - scale.to_percent(peak));
+                cr.move_to(bounds.x, y);
+                cr.line_to(bounds.x + bounds.width, y);
+            }
+            cr.stroke();
+            cr.restore();
         }
 
         self.set_background(cr, gauge, state);
@@ -340,7 +480,7 @@ impl CairoRenderer {
         self.show_outline(cr, gauge, scale.3, state);
 
         if self.set_indicator(cr, gauge, state) {
-            if let Some(value) = state.get(&gauge.channel) {
+            if let Some(value) = self.value(gauge, state) {
                 let bounds = bounds.inset(1.0);
                 cr.save();
                 cr.rectangle(
@@ -355,6 +495,16 @@ impl CairoRenderer {
                 cr.fill();
                 cr.restore();
             }
+
+            cr.save();
+            cr.set_line_width(3.0);
+            for peak in self.peaks(gauge, scale, state) {
+                let x = bounds.x + bounds.width * scale.to_percent(peak);
+                cr.move_to(x, bounds.y);
+                cr.line_to(x, bounds.y + bounds.height);
+            }
+            cr.stroke();
+            cr.restore();
         }
 
         self.set_background(cr, gauge, state);
@@ -378,9 +528,11 @@ impl CairoRenderer {
 
         if self.set_indicator(cr, gauge, state) {
             let (cx, cy) = bounds.center();
-            let label = if let Some(value) = state.get(&gauge.channel) {
+            let label = if let Some(value) = self.value(gauge, state) {
                 let formatted = format.format_value(value);
                 gauge.label.append(&formatted)
+            } else if state.stale.contains(&gauge.channel) {
+                gauge.label.append(&String::from("---"))
             } else {
                 gauge.label.clone()
             };
@@ -405,7 +557,7 @@ impl PNGRenderer {
         PNGRenderer {renderer, path, screen}
     }
 
-    pub fn render(&self, state: &State) {
+    pub fn render(&self, state: &State, time: Float) {
         let surface = ImageSurface::create(
             Format::ARgb32,
             self.screen.width as i32,
@@ -413,7 +565,7 @@ impl PNGRenderer {
         ).expect("Couldn't create surface.");
         let cr = Context::new(&surface);
 
-        self.renderer.render(&cr, state);
+        self.renderer.render(&cr, state, time);
         let mut file = File::create(self.path.clone())
             .expect("couldn't create file");
 
@@ -0,0 +1,335 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Log replay
+//
+// Plays back a log written by the logger, in its `Lines` format, with
+// the samples spaced according to their recorded timestamps. Lines
+// without a timestamp are given the time of the line before.
+//
+// The log is read into memory up front, so that playback can seek
+// freely. Playback is controlled by sending it `Command`s, which can
+// also be read from a stream, one per line: `pause`, `resume`,
+// `seek <seconds>`, `speed <multiplier>`, and `loop on|off`. Seek
+// times are relative to the start of the log.
+//
+// Samples keep their recorded timestamps, so seeking backwards moves
+// time backwards, which stateful channels treat as no time passing.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+    thread::{sleep, spawn},
+    time::Duration
+};
+
+use crate::config::{Channel, Config, Float};
+use crate::data::{parse, DataSource, Sample, Shared, State};
+use crate::pipeline::Pipeline;
+
+// Longest the player sleeps before checking for commands.
+const MAX_WAIT: Float = 0.05;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    // Jump to the given number of seconds from the start of the log.
+    Seek(Float),
+    // Set the playback speed, as a multiple of real time.
+    Speed(Float),
+    // Whether to start over at the end of the log.
+    Loop(bool)
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Option<Command> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["pause"] => Some(Command::Pause),
+            ["resume"] => Some(Command::Resume),
+            ["seek", t] => t.parse().ok().map(Command::Seek),
+            ["speed", x] => x
+                .parse()
+                .ok()
+                .filter(|x: &Float| *x > 0.0)
+                .map(Command::Speed),
+            ["loop", "on"] => Some(Command::Loop(true)),
+            ["loop", "off"] => Some(Command::Loop(false)),
+            _ => None
+        }
+    }
+}
+
+// Read the samples from a log. A sample without a time is taken to
+// follow the one before it.
+pub fn load<R: Read>(src: R) -> io::Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    let mut time = 0.0;
+
+    for line in BufReader::new(src).lines() {
+        let line = line?;
+        if let Ok((mut sample, _)) = parse(line.trim(), time, &None) {
+            if let Some(t) = sample.values.remove("time") {
+                sample.time = t;
+            }
+            time = sample.time;
+            samples.push(sample);
+        }
+    }
+
+    Ok(samples)
+}
+
+// Playback position, in log time.
+struct Player {
+    samples: Vec<Sample>,
+    // Index of the next sample to play.
+    position: usize,
+    speed: Float,
+    looping: bool,
+    paused: bool,
+    // A log time, and the local time at which it was reached.
+    anchor: (Float, Float),
+    ended: bool
+}
+
+impl Player {
+    fn start(&self) -> Float {
+        self.samples.first().map(|s| s.time).unwrap_or(0.0)
+    }
+
+    // The log time reached at the given local time.
+    fn time(&self, now: Float) -> Float {
+        let (time, then) = self.anchor;
+        if self.paused {
+            time
+        } else {
+            time + (now - then) * self.speed
+        }
+    }
+
+    fn apply(&mut self, command: Command, now: Float, sink: &Shared) {
+        self.anchor = (self.time(now), now);
+
+        match command {
+            Command::Pause => self.paused = true,
+            Command::Resume => self.paused = false,
+            Command::Seek(offset) => {
+                let time = self.start() + offset.max(0.0);
+                self.position = self.samples
+                    .iter()
+                    .position(|s| s.time >= time)
+                    .unwrap_or(self.samples.len());
+                self.anchor = (time, now);
+                if self.ended && self.position < self.samples.len() {
+                    self.ended = false;
+                    sink.restart();
+                }
+            },
+            Command::Speed(speed) => self.speed = speed,
+            Command::Loop(looping) => self.looping = looping
+        }
+    }
+
+    // Merge every sample due by the given local time, and return how
+    // long to wait for the next one.
+    fn play(&mut self, now: Float, sink: &Shared) -> Float {
+        let time = self.time(now);
+
+        while let Some(sample) = self.samples.get(self.position) {
+            if sample.time > time {
+                break;
+            }
+            sink.merge(Sample {received: now, ..sample.clone()});
+            self.position += 1;
+        }
+
+        if self.position >= self.samples.len() {
+            if self.looping && !self.samples.is_empty() {
+                self.position = 0;
+                self.anchor = (self.start(), now);
+            } else if !self.ended {
+                self.ended = true;
+                sink.end();
+            }
+        }
+
+        match self.samples.get(self.position) {
+            Some(next) if !self.paused && !self.ended =>
+                ((next.time - time) / self.speed).clamp(0.0, MAX_WAIT),
+            _ => MAX_WAIT
+        }
+    }
+}
+
+pub struct ReplaySource {
+    shared: Shared,
+    channels: Vec<Channel>,
+    commands: Sender<Command>
+}
+
+impl ReplaySource {
+    pub fn new(
+        path: &str,
+        config: &Config,
+        speed: Float,
+        looping: bool
+    ) -> io::Result<ReplaySource> {
+        let samples = load(File::open(path)?)?;
+        // Replayed samples are not logged again.
+        let shared = Shared::with_pipeline(
+            State::from_config(config),
+            Pipeline::from_config(config)
+        );
+        let sink = shared.clone();
+        let (commands, receiver) = channel();
+
+        spawn(move || {
+            let now = sink.now();
+            let mut player = Player {
+                samples,
+                position: 0,
+                speed,
+                looping,
+                paused: false,
+                anchor: (0.0, now),
+                ended: false
+            };
+            player.anchor = (player.start(), now);
+
+            run(player, receiver, sink);
+        });
+
+        Ok(ReplaySource {shared, channels: config.channels.clone(), commands})
+    }
+
+    // Return a handle by which playback can be controlled.
+    pub fn commands(&self) -> Sender<Command> {
+        self.commands.clone()
+    }
+
+    // Read commands from the given stream, one per line.
+    pub fn read_commands<R>(&self, src: R) where R: Read + Send + 'static {
+        let commands = self.commands();
+
+        spawn(move || {
+            for line in BufReader::new(src).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break
+                };
+
+                match Command::parse(&line) {
+                    Some(command) => if commands.send(command).is_err() {
+                        break;
+                    },
+                    None => eprintln!("Unknown replay command: {}", line.trim())
+                }
+            }
+        });
+    }
+}
+
+fn run(mut player: Player, commands: Receiver<Command>, sink: Shared) {
+    loop {
+        let wait = player.play(sink.now(), &sink);
+        let wait = Duration::from_millis((wait * 1000.0) as u64);
+
+        match commands.recv_timeout(wait) {
+            Ok(command) => player.apply(command, sink.now(), &sink),
+            Err(RecvTimeoutError::Timeout) => (),
+            // Nothing can control playback any more, so play to the end.
+            Err(RecvTimeoutError::Disconnected) => if player.ended {
+                break;
+            } else {
+                sleep(wait);
+            }
+        }
+    }
+}
+
+impl DataSource for ReplaySource {
+    fn get_state(&self) -> State {
+        self.shared.snapshot(&self.channels)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(Command::parse("pause"), Some(Command::Pause));
+        assert_eq!(Command::parse(" seek 12.5 "), Some(Command::Seek(12.5)));
+        assert_eq!(Command::parse("speed 2"), Some(Command::Speed(2.0)));
+        assert_eq!(Command::parse("speed -1"), None);
+        assert_eq!(Command::parse("loop on"), Some(Command::Loop(true)));
+        assert_eq!(Command::parse("rewind"), None);
+    }
+
+    #[test]
+    fn test_playback() {
+        let log = "{\"time\":10,\"RPM\":1000}\n\
+                   {\"RPM\":1500}\n\
+                   not a sample\n\
+                   {\"time\":11,\"RPM\":2000}\n\
+                   {\"time\":12,\"RPM\":2500}\n";
+        let samples = load(log.as_bytes()).unwrap();
+        let times: Vec<Float> = samples.iter().map(|s| s.time).collect();
+        assert_eq!(times, vec! {10.0, 10.0, 11.0, 12.0});
+
+        let sink = Shared::new(State::new());
+        let mut player = Player {
+            samples,
+            position: 0,
+            speed: 2.0,
+            looping: false,
+            paused: false,
+            anchor: (10.0, 0.0),
+            ended: false
+        };
+
+        player.play(0.0, &sink);
+        assert_eq!(player.position, 2);
+        assert_eq!(sink.snapshot(&[]).get(&String::from("RPM")), Some(1500.0));
+
+        // At double speed, the sample at 11s is due after half a second.
+        assert_eq!(player.play(0.25, &sink), 0.05);
+        assert_eq!(player.position, 2);
+        player.play(0.5, &sink);
+        assert_eq!(player.position, 3);
+
+        player.apply(Command::Pause, 0.6, &sink);
+        player.play(5.0, &sink);
+        assert_eq!(player.position, 3);
+
+        player.apply(Command::Resume, 5.0, &sink);
+        player.play(5.5, &sink);
+        assert!(player.ended);
+        assert!(sink.snapshot(&[]).source_lost);
+
+        player.apply(Command::Seek(1.0), 6.0, &sink);
+        assert_eq!(player.position, 2);
+        assert!(!player.ended);
+        assert!(!sink.snapshot(&[]).source_lost);
+    }
+}
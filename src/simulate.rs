@@ -0,0 +1,357 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Signal simulator
+//
+// Synthesizes samples from a spec, written in RON, for bench testing
+// without any hardware. Each signal generates the values of one input,
+// and events override inputs for a while, to script scenarios such as
+// a drop in oil pressure.
+//
+// Samples are stamped with the simulated time, counted from zero at
+// `rate` samples per second, and random signals use a fixed seed, so
+// the same spec always produces the same samples.
+
+use std::{
+    collections::HashMap,
+    f64::consts::PI,
+    fs::File,
+    io,
+    thread::sleep,
+    time::Duration
+};
+
+use ron::de::from_reader;
+use serde::Deserialize;
+
+use crate::config::{Config, Float};
+use crate::data::{Sample, ThreadedSource};
+
+#[derive(Deserialize, Debug, Clone)]
+pub enum Wave {
+    Const(Float),
+    Sine {min: Float, max: Float, period: Float},
+    // Rises from `from` to `to` over each period, then starts over.
+    Ramp {from: Float, to: Float, period: Float},
+    // `high` for the first `duty` fraction of each period.
+    Square {low: Float, high: Float, period: Float, duty: Option<Float>},
+    // Moves up to `step` either way each sample.
+    RandomWalk {start: Float, step: Float, min: Float, max: Float},
+    // `(time, value)` pairs, each value held until the next.
+    Steps(Vec<(Float, Float)>)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Signal {
+    pub name: String,
+    pub wave: Wave
+}
+
+// Sets inputs to fixed values from `start` until `end`, if given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Event {
+    pub start: Float,
+    pub end: Option<Float>,
+    pub values: HashMap<String, Float>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Simulation {
+    // Samples per second, 20 by default.
+    pub rate: Option<Float>,
+    pub seed: Option<u64>,
+    // Seconds after which the simulation ends, if any.
+    pub duration: Option<Float>,
+    pub signals: Vec<Signal>,
+    pub events: Option<Vec<Event>>
+}
+
+impl Wave {
+    pub fn is_valid(&self) -> bool {
+        let positive = |x: Float| x.is_finite() && x > 0.0;
+        match self {
+            Wave::Sine {period, ..} | Wave::Ramp {period, ..} => positive(*period),
+            Wave::Square {period, duty, ..} =>
+                positive(*period) && (0.0..=1.0).contains(&duty.unwrap_or(0.5)),
+            Wave::RandomWalk {min, max, ..} => min <= max,
+            Wave::Const(_) | Wave::Steps(_) => true
+        }
+    }
+}
+
+impl Simulation {
+    pub fn is_valid(&self) -> bool {
+        let rate = match self.rate {
+            Some(rate) => rate.is_finite() && rate > 0.0,
+            None => true
+        };
+
+        rate && self.signals.iter().all(|s| s.wave.is_valid())
+    }
+}
+
+pub fn load(path: &str) -> io::Result<Simulation> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let simulation: Simulation = from_reader(File::open(path)?)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    if simulation.is_valid() {
+        Ok(simulation)
+    } else {
+        Err(invalid(String::from(
+            "rates and periods must be positive, duties at most one, and minimums at most maximums"
+        )))
+    }
+}
+
+// Xorshift pseudo-random numbers, which are plenty for test signals.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // Xorshift gets stuck at zero.
+        Rng(seed.max(1))
+    }
+
+    // A number in [0, 1).
+    fn next(&mut self) -> Float {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 11) as Float / (1u64 << 53) as Float
+    }
+}
+
+// Fraction of the way through the current period.
+fn phase(time: Float, period: Float) -> Float {
+    (time / period).rem_euclid(1.0)
+}
+
+pub struct Simulator {
+    simulation: Simulation,
+    rng: Rng,
+    // Current value of each random walk.
+    walks: HashMap<String, Float>
+}
+
+impl Simulator {
+    pub fn new(simulation: Simulation) -> Simulator {
+        let rng = Rng::new(simulation.seed.unwrap_or(1));
+        Simulator {simulation, rng, walks: HashMap::new()}
+    }
+
+    pub fn rate(&self) -> Float {
+        self.simulation.rate.unwrap_or(20.0)
+    }
+
+    // Whether the simulation has ended by the given time.
+    pub fn ended(&self, time: Float) -> bool {
+        match self.simulation.duration {
+            Some(duration) => time > duration,
+            None => false
+        }
+    }
+
+    // Generate the values of every input at the given time.
+    pub fn sample(&mut self, time: Float) -> HashMap<String, Float> {
+        let mut values = HashMap::new();
+
+        for signal in &self.simulation.signals {
+            let value = match &signal.wave {
+                Wave::Const(value) => *value,
+                Wave::Sine {min, max, period} =>
+                    min + (max - min) * 0.5 * (1.0 + (2.0 * PI * time / period).sin()),
+                Wave::Ramp {from, to, period} =>
+                    from + (to - from) * phase(time, *period),
+                Wave::Square {low, high, period, duty} =>
+                    if phase(time, *period) < duty.unwrap_or(0.5) {*high} else {*low},
+                Wave::RandomWalk {start, step, min, max} => {
+                    let delta = step * (2.0 * self.rng.next() - 1.0);
+                    let walk = self.walks.entry(signal.name.clone()).or_insert(*start);
+                    *walk = (*walk + delta).clamp(*min, *max);
+                    *walk
+                },
+                Wave::Steps(steps) => match steps.iter().rev().find(|s| s.0 <= time) {
+                    Some((_, value)) => *value,
+                    None => match steps.first() {
+                        Some((_, value)) => *value,
+                        None => continue
+                    }
+                }
+            };
+
+            values.insert(signal.name.clone(), value);
+        }
+
+        for event in self.simulation.events.iter().flatten() {
+            let ended = match event.end {
+                Some(end) => time >= end,
+                None => false
+            };
+
+            if time >= event.start && !ended {
+                for (name, value) in &event.values {
+                    values.insert(name.clone(), *value);
+                }
+            }
+        }
+
+        values
+    }
+}
+
+// Play the simulation in real time.
+pub fn source(simulation: Simulation, config: &Config) -> ThreadedSource {
+    ThreadedSource::spawn(config, move |sink, _| {
+        let mut simulator = Simulator::new(simulation);
+        let rate = simulator.rate();
+        let start = sink.now();
+
+        for n in 0u64.. {
+            let time = n as Float / rate;
+            if simulator.ended(time) {
+                break;
+            }
+
+            let values = simulator.sample(time);
+            sink.merge(Sample {values, time, received: sink.now()});
+
+            let wait = start + (n + 1) as Float / rate - sink.now();
+            if wait > 0.0 {
+                sleep(Duration::from_millis((wait * 1000.0) as u64));
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Condition, Logic, Test, When};
+    use crate::config;
+    use crate::data::State;
+    use crate::logic::Evaluator;
+
+    fn signal(name: &str, wave: Wave) -> Signal {
+        Signal {name: String::from(name), wave}
+    }
+
+    fn simulation(signals: Vec<Signal>, events: Vec<Event>) -> Simulation {
+        Simulation {
+            rate: Some(10.0),
+            seed: Some(42),
+            duration: None,
+            signals,
+            events: Some(events)
+        }
+    }
+
+    #[test]
+    fn test_waves() {
+        let mut simulator = Simulator::new(simulation(vec! {
+            signal("SINE", Wave::Sine {min: 0.0, max: 10.0, period: 4.0}),
+            signal("RAMP", Wave::Ramp {from: 0.0, to: 10.0, period: 4.0}),
+            signal("SQUARE", Wave::Square {low: 0.0, high: 1.0, period: 4.0, duty: None}),
+            signal("STEPS", Wave::Steps(vec! {(1.0, 5.0), (3.0, 7.0)}))
+        }, Vec::new()));
+
+        for (time, sine, ramp, square, steps) in &[
+            (0.0, 5.0, 0.0, 1.0, 5.0),
+            (1.0, 10.0, 2.5, 1.0, 5.0),
+            (3.0, 0.0, 7.5, 0.0, 7.0),
+            (5.0, 10.0, 2.5, 1.0, 7.0)
+        ] {
+            let values = simulator.sample(*time);
+            assert!((values["SINE"] - sine).abs() < 1e-9);
+            assert_eq!(values["RAMP"], *ramp);
+            assert_eq!(values["SQUARE"], *square);
+            assert_eq!(values["STEPS"], *steps);
+        }
+
+        // Random walks are repeatable, and stay within bounds.
+        let walk = || {
+            let mut simulator = Simulator::new(simulation(vec! {
+                signal("FUEL", Wave::RandomWalk {start: 50.0, step: 5.0, min: 45.0, max: 55.0})
+            }, Vec::new()));
+            (0..100)
+                .map(|n| simulator.sample(n as Float)["FUEL"])
+                .collect::<Vec<Float>>()
+        };
+        assert_eq!(walk(), walk());
+        assert!(walk().iter().all(|x| (45.0..=55.0).contains(x)));
+    }
+
+    #[test]
+    fn test_validation() {
+        let sine = signal("SINE", Wave::Sine {min: 0.0, max: 1.0, period: 1.0});
+        assert!(simulation(vec! {sine.clone()}, Vec::new()).is_valid());
+        assert!(!Simulation {rate: Some(0.0), ..simulation(vec! {sine}, Vec::new())}.is_valid());
+        assert!(!simulation(vec! {
+            signal("RAMP", Wave::Ramp {from: 0.0, to: 1.0, period: 0.0})
+        }, Vec::new()).is_valid());
+        assert!(!simulation(vec! {
+            signal("FUEL", Wave::RandomWalk {start: 0.0, step: 1.0, min: 1.0, max: 0.0})
+        }, Vec::new()).is_valid());
+    }
+
+    #[test]
+    fn test_alarm_scenario() {
+        let logic: Logic = vec! {
+            When(
+                Condition::Debounce(
+                    Box::new(Condition::Is(
+                        String::from("OIL_PRESSURE"),
+                        Test::LessThan(20.0)
+                    )),
+                    0.5,
+                    1.0
+                ),
+                config::State::Alarm(String::from("OIL_PRESSURE_LOW"))
+            )
+        };
+
+        let mut simulator = Simulator::new(simulation(vec! {
+            signal("OIL_PRESSURE", Wave::Const(45.0))
+        }, vec! {
+            Event {
+                start: 2.0,
+                end: Some(4.0),
+                values: vec! {(String::from("OIL_PRESSURE"), 5.0)}
+                    .into_iter()
+                    .collect()
+            }
+        }));
+
+        let evaluator = Evaluator::new(logic);
+        let mut state = State::new();
+        let name = String::from("OIL_PRESSURE_LOW");
+
+        for n in 0..60 {
+            let time = n as Float / simulator.rate();
+            let values = simulator.sample(time);
+            state.update(Sample {values, time, received: time});
+            evaluator.update(&mut state);
+
+            // The alarm is raised half a second into the pressure drop,
+            // and cleared a second after pressure recovers.
+            let expected = (25..50).contains(&n);
+            assert_eq!(state.states[&name], expected, "at {}s", time);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+
+// Helpers shared by the tests of the data sources.
+
+use std::{
+    thread::sleep,
+    time::{Duration, Instant}
+};
+
+use crate::data::{DataSource, State};
+
+// Wait for the source's state to satisfy the predicate.
+pub fn wait_for<F>(source: &dyn DataSource, predicate: F) -> State
+where F: Fn(&State) -> bool {
+    let start = Instant::now();
+    loop {
+        let state = source.get_state();
+        if predicate(&state) || start.elapsed() > Duration::from_secs(5) {
+            return state;
+        }
+        sleep(Duration::from_millis(10));
+    }
+}
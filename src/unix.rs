@@ -0,0 +1,217 @@
+// uDashBoard: featherweight dashboard application.
+//
+// Copyright (C) 2019  Brandon Lewis
+//
+// This program is free software: you can redistribute it and/or
+// modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of
+// the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public
+// License along with this program.  If not, see
+// <https://www.gnu.org/licenses/>.
+
+// Unix socket data source
+//
+// Listens on a Unix domain socket, so that separate producers, such as
+// GPS, CAN and analog input daemons, can each connect and send the
+// same line-delimited JSON as `read_source`. Every connection is read
+// on its own thread, and all are merged into one state.
+//
+// A producer may name itself with a first line of the form
+// `{"producer": "gps"}`. Otherwise it is named for the order in which
+// it connected, e.g. `#3`. A producer which gives the name of one that
+// is still connected is refused. Each channel is attributed to the
+// producer which last updated it, and when a producer disconnects, the
+// channels attributed to it are stale until they are updated again.
+//
+// The source itself never ends: producers may come and go for as long
+// as the dashboard runs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream}
+    },
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::spawn
+};
+
+use serde_json;
+use serde_json::Value;
+
+use crate::config::{Config, Input};
+use crate::data::{merge_line, read_lines, Shared, ThreadedSource};
+
+// Listen on the socket at the given path. A socket left behind by an
+// earlier run is replaced, but any other file is an error.
+pub fn source(path: &str, config: &Config) -> io::Result<ThreadedSource> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+
+    let listener = UnixListener::bind(Path::new(path))?;
+
+    Ok(ThreadedSource::spawn(config, move |sink, input| {
+        accept(listener, sink, input)
+    }))
+}
+
+// Names of the producers which are connected.
+type Connected = Arc<Mutex<HashSet<String>>>;
+
+fn accept(listener: UnixListener, sink: &Shared, input: &Input) {
+    let connected = Connected::default();
+
+    for (n, stream) in listener.incoming().enumerate() {
+        match stream {
+            Ok(stream) => {
+                let sink = sink.clone();
+                let input = input.clone();
+                let connected = connected.clone();
+                spawn(move || read_producer(stream, n + 1, &sink, &input, &connected));
+            },
+            Err(e) => if input.log_errors {
+                eprintln!("Error accepting connection: {}", e);
+            }
+        }
+    }
+}
+
+// The name given by a producer's first line, if it gives one.
+fn producer_name(line: &[u8]) -> Option<String> {
+    let line = std::str::from_utf8(line).ok()?;
+    let map: HashMap<String, Value> = serde_json::from_str(line).ok()?;
+
+    if map.len() == 1 {
+        map.get("producer")?.as_str().map(String::from)
+    } else {
+        None
+    }
+}
+
+fn read_producer<R: Read>(
+    src: R,
+    n: usize,
+    sink: &Shared,
+    input: &Input,
+    connected: &Connected
+) {
+    let mut reader = BufReader::new(src);
+    let mut first = Vec::new();
+
+    if let Err(e) = reader.read_until(b'\n', &mut first) {
+        if input.log_errors {
+            eprintln!("Error reading producer #{}: {}", n, e);
+        }
+        return;
+    }
+
+    let (name, first) = match producer_name(&first) {
+        Some(name) => (name, None),
+        None => (format!("#{}", n), Some(first))
+    };
+
+    if !connected.lock().unwrap().insert(name.clone()) {
+        if input.log_errors {
+            eprintln!("Producer {} is already connected.", name);
+        }
+        return;
+    }
+
+    if input.log_errors {
+        eprintln!("Producer {} connected.", name);
+    }
+
+    let sink = sink.producer(&name);
+    if let Some(line) = first {
+        merge_line(&line, &sink, input);
+    }
+    read_lines(reader, &sink, input);
+    sink.disconnect();
+    connected.lock().unwrap().remove(&name);
+
+    if input.log_errors {
+        eprintln!("Producer {} disconnected.", name);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use crate::config::{Channel, Unit};
+    use crate::data::DataSource;
+    use crate::testing::wait_for;
+
+    #[test]
+    fn test_producer_name() {
+        assert_eq!(producer_name(b"{\"producer\": \"gps\"}\n"), Some(String::from("gps")));
+        assert_eq!(producer_name(b"{\"RPM\": 1000}\n"), None);
+        assert_eq!(producer_name(b"not json\n"), None);
+    }
+
+    #[test]
+    fn test_producers() {
+        let path = std::env::temp_dir()
+            .join(format!("udashboard-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let rpm = Channel {
+            name: String::from("RPM"),
+            units: Unit::None,
+            timeout: None,
+            input: None,
+            calibration: None,
+            filters: None
+        };
+        let speed = Channel {name: String::from("SPEED"), ..rpm.clone()};
+        let config = Config {channels: vec! {rpm, speed}, ..Config::empty()};
+        let source = source(path, &config).unwrap();
+
+        let mut can = UnixStream::connect(path).unwrap();
+        let mut gps = UnixStream::connect(path).unwrap();
+        writeln!(can, "{{\"producer\": \"can\"}}\n{{\"RPM\": 3000}}").unwrap();
+        writeln!(gps, "{{\"SPEED\": 55}}").unwrap();
+
+        let rpm = String::from("RPM");
+        let speed = String::from("SPEED");
+        let state = wait_for(&source, |s| s.get(&rpm).is_some() && s.get(&speed).is_some());
+        assert_eq!(state.producers[&rpm], "can");
+        assert_eq!(state.producers[&speed], "#2");
+        assert!(state.stale.is_empty());
+
+        // A second producer with the same name is refused.
+        let mut imposter = UnixStream::connect(path).unwrap();
+        writeln!(imposter, "{{\"producer\": \"can\"}}\n{{\"RPM\": 9000}}").unwrap();
+        imposter.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(source.get_state().get(&rpm), Some(3000.0));
+
+        // Losing one producer makes only its channels stale.
+        drop(can);
+        let state = wait_for(&source, |s| !s.stale.is_empty());
+        assert!(state.stale.contains(&rpm));
+        assert!(!state.stale.contains(&speed));
+        assert!(!state.source_lost);
+
+        // Until it reconnects, and updates them.
+        let mut can = UnixStream::connect(path).unwrap();
+        writeln!(can, "{{\"producer\": \"can\"}}\n{{\"RPM\": 3500}}").unwrap();
+        let state = wait_for(&source, |s| s.stale.is_empty());
+        assert_eq!(state.get(&rpm), Some(3500.0));
+
+        fs::remove_file(path).unwrap();
+    }
+}
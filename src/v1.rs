@@ -25,17 +25,34 @@ use std::{
 };
 use serde::{Deserialize};
 
+use crate::ast;
+use crate::ast::{BinOp, Node, TypeTag, UnOp};
 use crate::config;
 use crate::config::{
+    Blink,
     Bounds,
+    CanSignal,
     Channel,
+    Condition,
     Config,
+    Derived,
     Float,
     GaugeType,
+    Input,
+    InputFormat,
+    Log,
+    Obd,
     Screen,
     State,
-    Logic
+    Logic,
+    Test,
+    TimeChannel,
+    Timestamp,
+    Unit,
+    When
 };
+use crate::env::Env;
+use crate::typechecker::{TypeChecker, TypeError};
 
 #[derive(Deserialize, Debug, Copy, Clone)]
 enum Color {
@@ -299,12 +316,82 @@ impl Gauge {
 #[derive(Deserialize, Debug)]
 struct Page(Vec<String>);
 
+// Arithmetic over channel values.
+#[derive(Deserialize, Debug, Clone)]
+enum Expr {
+    Const(Float),
+    Chan(String),
+    BinOp(BinOp, Box<Expr>, Box<Expr>),
+    UnOp(UnOp, Box<Expr>)
+}
+
+impl Expr {
+    pub fn to_ast(self) -> ast::Expr {
+        match self {
+            Expr::Const(x) => ast::Expr::Float(x),
+            Expr::Chan(name) => ast::Expr::Id(name),
+            Expr::BinOp(op, l, r) => ast::Expr::BinOp(
+                op,
+                Node::new(l.to_ast()),
+                Node::new(r.to_ast())
+            ),
+            Expr::UnOp(op, x) => ast::Expr::UnOp(op, Node::new(x.to_ast()))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct DerivedChannel {
+    name: String,
+    units: Unit,
+    expr: Expr
+}
+
+impl DerivedChannel {
+    pub fn to_config(self) -> Derived {
+        Derived {
+            name: self.name,
+            units: self.units,
+            expr: self.expr.to_ast()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+enum Rule {
+    // Shorthand for testing a single channel.
+    When(String, Test, State),
+    If(Condition, State)
+}
+
+impl Rule {
+    pub fn to_config(self) -> When {
+        match self {
+            Rule::When(channel, test, state) =>
+                When(Condition::Is(channel, test), state),
+            Rule::If(cond, state) => When(cond, state)
+        }
+    }
+}
+
 #[derive(Deserialize, Debug)]
 struct V1 {
     width: u32,
     height: u32,
+    frame_rate: Option<Float>,
+    slow_blink: Option<Blink>,
+    fast_blink: Option<Blink>,
+    timestamp: Option<Timestamp>,
+    log_errors: Option<bool>,
+    input_format: Option<InputFormat>,
     channels: Vec<Channel>,
-    conditions: Logic,
+    derived: Option<Vec<DerivedChannel>>,
+    time_channels: Option<Vec<TimeChannel>>,
+    stats_reset: Option<String>,
+    log: Option<Log>,
+    can_signals: Option<Vec<CanSignal>>,
+    obd: Option<Obd>,
+    conditions: Vec<Rule>,
     gauges: Vec<Gauge>,
     pages: Vec<Page>,
     styles: StyleDefs
@@ -316,21 +403,59 @@ pub enum V1Error {
     ParseError(String),
     NoSuchChannel(String),
     NoSuchState(State),
-    NoSuchGauge(String)
+    NoSuchGauge(String),
+    CyclicCondition(Vec<String>),
+    InvalidCalibration(String),
+    InvalidCondition(State),
+    InvalidFrameRate(Float),
+    InvalidFilter(String),
+    InvalidCanSignal(String),
+    InvalidObd,
+    InvalidLog,
+    InvalidExpression(String, TypeError)
 }
 
 impl V1 {
-    fn to_config(self) -> Config {
+    fn to_config(mut self) -> Config {
         let width = self.width as Float;
         let height = self.height as Float;
         let screen = Screen {width, height};
+        let frame_rate = self.frame_rate.unwrap_or(30.0);
+        let slow_blink = self.slow_blink.unwrap_or_else(Blink::slow);
+        let fast_blink = self.fast_blink.unwrap_or_else(Blink::fast);
+        let input = Input {
+            timestamp: self.timestamp.clone(),
+            log_errors: self.log_errors.unwrap_or(false),
+            format: self.input_format.unwrap_or(InputFormat::Auto)
+        };
+        let derived = self.derived
+            .take()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|d| d.to_config())
+            .collect();
+        let time_channels = self.time_channels.take().unwrap_or_default();
+        let stats_reset = self.stats_reset.take();
+        let log = self.log.take();
+        let can_signals = self.can_signals.take().unwrap_or_default();
+        let obd = self.obd.take();
         let (pages, channels, conditions) = self.build_page_list(screen);
 
         Config {
             screen: screen,
+            frame_rate: frame_rate,
+            slow_blink: slow_blink,
+            fast_blink: fast_blink,
             channels: channels,
+            derived: derived,
+            time_channels: time_channels,
+            stats_reset: stats_reset,
             pages: pages,
-            logic: conditions
+            logic: conditions,
+            input: input,
+            log: log,
+            can_signals: can_signals,
+            obd: obd
         }
     }
 
@@ -359,13 +484,52 @@ impl V1 {
             ret.push(temp);
         }
 
-        (ret, self.channels, self.conditions)
+        let logic = self.conditions
+            .into_iter()
+            .map(|rule| rule.to_config())
+            .collect();
+
+        (ret, self.channels, logic)
     }
 
     fn validate(self) -> Result<Config, V1Error> {
         // check all channels, states, and gauges have unique names
         // check that all condition tests are based on defined values
-        // check that condition graph is acyclic
+        if let Some(rate) = self.frame_rate {
+            if !rate.is_finite() || rate <= 0.0 {
+                return Err(V1Error::InvalidFrameRate(rate));
+            }
+        }
+        check_conditions(&self.conditions)?;
+        check_calibrations(&self.channels)?;
+        if let Some(signals) = &self.can_signals {
+            if let Some(signal) = signals.iter().find(|s| !s.is_valid()) {
+                return Err(V1Error::InvalidCanSignal(signal.name.clone()));
+            }
+        }
+        if let Some(obd) = &self.obd {
+            if !obd.is_valid() {
+                return Err(V1Error::InvalidObd);
+            }
+        }
+        if let Some(log) = &self.log {
+            if !log.is_valid() {
+                return Err(V1Error::InvalidLog);
+            }
+        }
+        let mut known = channel_names(&self.channels);
+        if let Some(time_channels) = &self.time_channels {
+            check_time_channels(&known, time_channels)?;
+            known.extend(time_channels.iter().map(|c| c.name.clone()));
+        }
+        if let Some(reset) = &self.stats_reset {
+            if !known.contains(reset) {
+                return Err(V1Error::NoSuchChannel(reset.clone()));
+            }
+        }
+        if let Some(derived) = &self.derived {
+            check_derived(known, derived)?;
+        }
         // check that all gauges use a defined channel
         // check that all states within a gauge are mutually exclusive
         // warn about unused channels
@@ -376,8 +540,195 @@ impl V1 {
 }
 
 
+// Check that every lookup table used for calibration is well-formed,
+// and that every filter has sensible parameters.
+fn check_calibrations(channels: &[Channel]) -> Result<(), V1Error> {
+    for channel in channels {
+        if let Some(calibration) = &channel.calibration {
+            if !calibration.is_valid() {
+                return Err(V1Error::InvalidCalibration(channel.name.clone()));
+            }
+        }
+        if let Some(filters) = &channel.filters {
+            if !filters.iter().all(|f| f.is_valid()) {
+                return Err(V1Error::InvalidFilter(channel.name.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The names of the given channels, including the unfiltered values
+// of those with filters.
+fn channel_names(channels: &[Channel]) -> Vec<String> {
+    let mut names = Vec::new();
+
+    for channel in channels {
+        names.push(channel.name.clone());
+        if channel.filters.is_some() {
+            names.push(format!("{}.raw", channel.name));
+        }
+    }
+
+    names
+}
+
+// Check that each time channel, and its reset, reads a known channel.
+fn check_time_channels(
+    known: &[String],
+    time_channels: &[TimeChannel]
+) -> Result<(), V1Error> {
+    for channel in time_channels {
+        let source = channel.function.source();
+        if !known.contains(source) {
+            return Err(V1Error::NoSuchChannel(source.clone()));
+        }
+        if let Some(reset) = &channel.reset {
+            if !known.contains(reset) {
+                return Err(V1Error::NoSuchChannel(reset.clone()));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Check that each derived channel is arithmetic over the known
+// channels, and the derived channels defined before it.
+fn check_derived(
+    known: Vec<String>,
+    derived: &[DerivedChannel]
+) -> Result<(), V1Error> {
+    let float = Node::new(TypeTag::Float);
+    let mut names = known;
+
+    for d in derived {
+        let env = Env::root();
+        for name in &names {
+            env.define(name, &float);
+        }
+
+        let result = TypeChecker::new(env).eval_expr(&d.expr.clone().to_ast());
+        let err = match result {
+            Ok(ref t) if *t == float => None,
+            Ok(t) => Some(TypeError::Mismatch(t, float.clone())),
+            Err(e) => Some(e)
+        };
+
+        if let Some(err) = err {
+            return Err(V1Error::InvalidExpression(d.name.clone(), err));
+        }
+
+        names.push(d.name.clone());
+    }
+
+    Ok(())
+}
+
+// Map each alarm to the names of the alarms its rules refer to.
+fn dependencies(rules: &[Rule]) -> HashMap<String, Vec<String>> {
+    let mut deps: HashMap<String, Vec<String>> = HashMap::new();
+
+    for rule in rules {
+        if let When(cond, State::Alarm(name)) = rule.clone().to_config() {
+            cond.references(deps.entry(name).or_insert_with(Vec::new));
+        }
+    }
+
+    deps
+}
+
+// Check that all condition references are defined, that the
+// condition graph is acyclic, and that every lookup table used by a
+// condition is well-formed.
+fn check_conditions(rules: &[Rule]) -> Result<(), V1Error> {
+    for rule in rules {
+        let When(cond, state) = rule.clone().to_config();
+        if !cond.is_valid() {
+            return Err(V1Error::InvalidCondition(state));
+        }
+    }
+
+    let deps = dependencies(rules);
+
+    for refs in deps.values() {
+        for name in refs {
+            if !deps.contains_key(name) {
+                return Err(V1Error::NoSuchState(State::Alarm(name.clone())));
+            }
+        }
+    }
+
+    let mut done = Vec::new();
+    for name in deps.keys() {
+        visit(name, &deps, &mut Vec::new(), &mut done)?;
+    }
+
+    Ok(())
+}
+
+// Depth-first search for cycles, starting from the given alarm.
+//
+// `path` holds the alarms on the current search path, and `done`
+// holds the alarms already known not to be part of any cycle.
+fn visit(
+    name: &String,
+    deps: &HashMap<String, Vec<String>>,
+    path: &mut Vec<String>,
+    done: &mut Vec<String>
+) -> Result<(), V1Error> {
+    if done.contains(name) {
+        return Ok(());
+    }
+
+    if let Some(start) = path.iter().position(|n| n == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name.clone());
+        return Err(V1Error::CyclicCondition(cycle));
+    }
+
+    path.push(name.clone());
+    for dep in &deps[name] {
+        visit(dep, deps, path, done)?;
+    }
+    path.pop();
+
+    done.push(name.clone());
+    Ok(())
+}
+
+
 pub fn load(path: String) -> Result<Config, V1Error> {
     let reader = File::open(path).expect("Couldn't open config");
     let config: V1 = from_reader(reader).unwrap();
     config.validate()
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derived() {
+        let rpm = || Box::new(Expr::Chan(String::from("RPM")));
+        let check = |expr| check_derived(vec! {String::from("RPM")}, &[DerivedChannel {
+            name: String::from("X"),
+            units: Unit::None,
+            expr
+        }]);
+
+        assert!(check(Expr::UnOp(UnOp::Neg, rpm())).is_ok());
+        assert!(check(Expr::BinOp(BinOp::Eq, rpm(), Box::new(Expr::Const(0.0)))).is_ok());
+        assert!(check(Expr::BinOp(BinOp::Gt, rpm(), Box::new(Expr::Const(6000.0)))).is_ok());
+
+        // Channels aren't booleans, so can't be negated.
+        assert!(check(Expr::UnOp(UnOp::Not, rpm())).is_err());
+        assert!(check(Expr::UnOp(UnOp::Not, Box::new(Expr::BinOp(
+            BinOp::Gt,
+            rpm(),
+            Box::new(Expr::Const(6000.0))
+        )))).is_err());
+    }
+}
@@ -1,12 +1,12 @@
 use crate::render::CairoRenderer;
-use crate::data::{State, ReadSource, DataSource};
+use crate::data::{State, DataSource};
 use crate::clock::Clock;
 use crate::config::Screen;
+use crate::logic::Evaluator;
 
 use gtk::prelude::*;
 use gtk::*;
 use cairo::*;
-use std::io::stdin;
 use std::process;
 use std::rc::Rc;
 use std::time::Instant;
@@ -27,13 +27,18 @@ fn draw(cr: &Context, time: f64) {
 }
 
 
-pub fn run(screen: Screen, renderer: CairoRenderer) {
+pub fn run<DS>(
+    screen: Screen,
+    renderer: CairoRenderer,
+    data: DS,
+    logic: Evaluator,
+    frame_rate: f64
+) where DS: DataSource + 'static {
     if gtk::init().is_err() {
         eprintln!("Failed to initialize GTK!");
         process::exit(1);
     }
 
-    let data = ReadSource::new(stdin());
     let clock = Clock::new();
     let window = Window::new(WindowType::Toplevel);
     let da = DrawingArea::new();
@@ -50,11 +55,13 @@ pub fn run(screen: Screen, renderer: CairoRenderer) {
     });
 
     da.connect_draw(move |_, cr| {
-        renderer.render(cr, &data.get_state());
+        let mut state = data.get_state();
+        logic.update(&mut state);
+        renderer.render(cr, &state, clock.seconds());
         Inhibit(true)
     });
 
-    gtk::idle_add(move || {
+    gtk::timeout_add((1000.0 / frame_rate) as u32, move || {
         da.queue_draw();
         Continue(true)
     });